@@ -1,31 +1,46 @@
 #![feature(exclusive_range_pattern)]
 use adventofcode2020::prelude::*;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+use std::time::Instant;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum Expression {
     Literal(i64),
+    Variable(String),
+    Neg(Box<Expression>),
     Add(Box<Expression>, Box<Expression>),
+    Sub(Box<Expression>, Box<Expression>),
     Mul(Box<Expression>, Box<Expression>),
+    Div(Box<Expression>, Box<Expression>),
+    Mod(Box<Expression>, Box<Expression>),
 }
 
 impl Display for Expression {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Expression::Literal(n) => f.write_fmt(format_args!("{}", n)),
-            Expression::Add(left, right) => {
-                f.write_char('(')?;
-                Self::fmt(left, f)?;
-                f.write_char('+')?;
-                Self::fmt(right, f)?;
-                f.write_char(')')?;
-
-                Ok(())
+            Expression::Variable(name) => f.write_str(name),
+            Expression::Neg(inner) => {
+                f.write_char('-')?;
+                if let Expression::Literal(_) = inner.as_ref() {
+                    // otherwise it would read as a negative literal
+                    f.write_char('(')?;
+                    Self::fmt(inner, f)?;
+                    f.write_char(')')
+                } else {
+                    Self::fmt(inner, f)
+                }
             }
-            Expression::Mul(left, right) => {
+            Expression::Add(left, right)
+            | Expression::Sub(left, right)
+            | Expression::Mul(left, right)
+            | Expression::Div(left, right)
+            | Expression::Mod(left, right) => {
                 f.write_char('(')?;
                 Self::fmt(left, f)?;
-                f.write_char('*')?;
+                f.write_char(self.operation().unwrap().symbol())?;
                 Self::fmt(right, f)?;
                 f.write_char(')')?;
 
@@ -36,253 +51,1186 @@ impl Display for Expression {
 }
 
 impl Expression {
-    fn evaluate(&self) -> i64 {
+    fn is_literal(&self) -> bool {
+        matches!(self, Expression::Literal(_))
+    }
+
+    fn pretty<'a>(&'a self, table: &'a OperatorTable) -> printer::Pretty<'a> {
+        printer::Pretty::new(self, table)
+    }
+
+    fn contains_variable(&self, variable: &str) -> bool {
+        match self {
+            Expression::Literal(_) => false,
+            Expression::Variable(name) => name == variable,
+            Expression::Neg(inner) => inner.contains_variable(variable),
+            Expression::Add(left, right)
+            | Expression::Sub(left, right)
+            | Expression::Mul(left, right)
+            | Expression::Div(left, right)
+            | Expression::Mod(left, right) => {
+                left.contains_variable(variable) || right.contains_variable(variable)
+            }
+        }
+    }
+
+    fn operation(&self) -> Option<Operation> {
+        match self {
+            Expression::Literal(_) | Expression::Variable(_) | Expression::Neg(_) => None,
+            Expression::Add(_, _) => Some(Operation::Add),
+            Expression::Sub(_, _) => Some(Operation::Sub),
+            Expression::Mul(_, _) => Some(Operation::Mul),
+            Expression::Div(_, _) => Some(Operation::Div),
+            Expression::Mod(_, _) => Some(Operation::Mod),
+        }
+    }
+
+    fn evaluate(&self) -> Result<i64> {
+        self.evaluate_with(&HashMap::new())
+    }
+
+    fn evaluate_with(&self, env: &HashMap<String, i64>) -> Result<i64> {
+        match self {
+            Expression::Literal(n) => Ok(*n),
+            Expression::Variable(name) => env
+                .get(name)
+                .copied()
+                .ok_or_else(|| Error::General(format!("Unbound variable {}", name))),
+            Expression::Neg(inner) => {
+                let n = inner.evaluate_with(env)?;
+                n.checked_neg()
+                    .ok_or_else(|| Error::General(format!("Overflow in -{}", n)))
+            }
+            Expression::Add(left, right)
+            | Expression::Sub(left, right)
+            | Expression::Mul(left, right)
+            | Expression::Div(left, right)
+            | Expression::Mod(left, right) => self
+                .operation()
+                .unwrap()
+                .apply(left.evaluate_with(env)?, right.evaluate_with(env)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl Operation {
+    fn from_symbol(symbol: char) -> Option<Self> {
+        match symbol {
+            '+' => Some(Operation::Add),
+            '-' => Some(Operation::Sub),
+            '*' => Some(Operation::Mul),
+            '/' => Some(Operation::Div),
+            '%' => Some(Operation::Mod),
+            _ => None,
+        }
+    }
+
+    fn symbol(self) -> char {
+        match self {
+            Operation::Add => '+',
+            Operation::Sub => '-',
+            Operation::Mul => '*',
+            Operation::Div => '/',
+            Operation::Mod => '%',
+        }
+    }
+
+    fn build(self, left: Expression, right: Expression) -> Expression {
+        let (left, right) = (Box::new(left), Box::new(right));
         match self {
-            Expression::Literal(n) => *n,
-            Expression::Add(left, right) => left.evaluate() + right.evaluate(),
-            Expression::Mul(left, right) => left.evaluate() * right.evaluate(),
+            Operation::Add => Expression::Add(left, right),
+            Operation::Sub => Expression::Sub(left, right),
+            Operation::Mul => Expression::Mul(left, right),
+            Operation::Div => Expression::Div(left, right),
+            Operation::Mod => Expression::Mod(left, right),
+        }
+    }
+
+    fn apply(self, left: i64, right: i64) -> Result<i64> {
+        if right == 0 && (self == Operation::Div || self == Operation::Mod) {
+            return Err(Error::General(format!(
+                "Division by zero in {}{}{}",
+                left,
+                self.symbol(),
+                right
+            )));
+        }
+        let result = match self {
+            Operation::Add => left.checked_add(right),
+            Operation::Sub => left.checked_sub(right),
+            Operation::Mul => left.checked_mul(right),
+            Operation::Div => left.checked_div(right),
+            Operation::Mod => left.checked_rem(right),
+        };
+        result.ok_or_else(|| {
+            Error::General(format!("Overflow in {}{}{}", left, self.symbol(), right))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Associativity {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+struct Operator {
+    operation: Operation,
+    precedence: u8,
+    associativity: Associativity,
+}
+
+impl Operator {
+    fn left(operation: Operation, precedence: u8) -> Self {
+        Self {
+            operation,
+            precedence,
+            associativity: Associativity::Left,
         }
     }
 }
 
-mod part1 {
+#[derive(Debug, Clone)]
+struct OperatorTable {
+    operators: Vec<Operator>,
+}
+
+impl OperatorTable {
+    fn new(operators: Vec<Operator>) -> Self {
+        Self { operators }
+    }
+
+    /// All operators with the same precedence, evaluated left to right.
+    fn part1() -> Self {
+        Self::new(vec![
+            Operator::left(Operation::Add, 1),
+            Operator::left(Operation::Sub, 1),
+            Operator::left(Operation::Mul, 1),
+            Operator::left(Operation::Div, 1),
+            Operator::left(Operation::Mod, 1),
+        ])
+    }
+
+    /// Addition binds tighter than multiplication.
+    fn part2() -> Self {
+        Self::new(vec![
+            Operator::left(Operation::Add, 2),
+            Operator::left(Operation::Sub, 2),
+            Operator::left(Operation::Mul, 1),
+            Operator::left(Operation::Div, 1),
+            Operator::left(Operation::Mod, 1),
+        ])
+    }
+
+    fn get(&self, symbol: char) -> Option<&Operator> {
+        self.operators
+            .iter()
+            .find(|op| op.operation.symbol() == symbol)
+    }
+}
+
+impl FromStr for OperatorTable {
+    type Err = Error;
+
+    /// Parses a comma separated list of `symbol precedence associativity`,
+    /// for example `+ 2 left, * 1 left`.
+    fn from_str(s: &str) -> Result<Self> {
+        let operators = s
+            .split(',')
+            .map(|entry| -> Result<Operator> {
+                let mut split = entry.split_ascii_whitespace();
+                let symbol = split
+                    .next()
+                    .ok_or_else(|| Error::General("Missing operator symbol".into()))?;
+                let mut chars = symbol.chars();
+                let operation = match (chars.next().and_then(Operation::from_symbol), chars.next())
+                {
+                    (Some(operation), None) => operation,
+                    _ => return Err(Error::General(format!("Unknown operator {}", symbol))),
+                };
+                let precedence = split
+                    .next()
+                    .ok_or_else(|| Error::General("Missing operator precedence".into()))?
+                    .parse::<u8>()?;
+                let associativity = match split.next() {
+                    None | Some("left") => Associativity::Left,
+                    Some("right") => Associativity::Right,
+                    Some(other) => {
+                        return Err(Error::General(format!("Invalid associativity {}", other)))
+                    }
+                };
+                if let Some(other) = split.next() {
+                    return Err(Error::General(format!(
+                        "Unexpected '{}' in operator definition",
+                        other
+                    )));
+                }
+                Ok(Operator {
+                    operation,
+                    precedence,
+                    associativity,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self::new(operators))
+    }
+}
+
+mod parser {
+    use super::Associativity;
     use super::Error;
     use super::Expression;
-    use super::Result;
+    use super::OperatorTable;
+    use std::fmt::{Display, Formatter};
+    use std::ops::Range;
 
-    fn parse_literal_or_nested<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        loop {
-            let t1 = tokens
-                .next()
-                .ok_or_else(|| Error::General("Unexpected EOF".into()))?;
-            match t1 {
-                ' ' => continue,
-                '0'..='9' => return Ok(Expression::Literal(t1 as i64 - '0' as i64)),
-                '(' => {
-                    let res = parse_expression(tokens)?;
-                    loop {
-                        let t2 = tokens
-                            .next()
-                            .ok_or_else(|| Error::General("Expected ')' but got EOF".into()))?;
-                        match t2 {
-                            ' ' => continue,
-                            ')' => return Ok(res),
-                            _ => {
-                                return Err(Error::General(format!(
-                                    "Expected ')' but got {:?}",
-                                    t2
-                                )))
-                            }
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Token {
+        // the magnitude only, so that `-9223372036854775808` can be parsed
+        Number(u64),
+        Identifier,
+        Operator(char),
+        Open,
+        Close,
+    }
+
+    impl Display for Token {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Token::Number(n) => f.write_fmt(format_args!("{}", n)),
+                Token::Identifier => f.write_str("identifier"),
+                Token::Operator(ch) => f.write_fmt(format_args!("'{}'", ch)),
+                Token::Open => f.write_str("'('"),
+                Token::Close => f.write_str("')'"),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub(super) struct ParseError {
+        message: String,
+        hint: Option<String>,
+        // byte offsets into the source
+        span: Range<usize>,
+        source: String,
+    }
+
+    impl Display for ParseError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            // tabs are kept, so that the caret lines up however wide they are displayed
+            let padding: String = self.source[..self.span.start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = self.source[self.span.start..self.span.end]
+                .chars()
+                .count()
+                .max(1);
+            f.write_fmt(format_args!("{}\n", self.message))?;
+            f.write_fmt(format_args!("  {}\n", self.source))?;
+            f.write_fmt(format_args!("  {}^{}", padding, "~".repeat(width - 1)))?;
+            if let Some(hint) = &self.hint {
+                f.write_fmt(format_args!("\nhint: {}", hint))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl From<ParseError> for Error {
+        fn from(e: ParseError) -> Self {
+            Error::General(e.to_string())
+        }
+    }
+
+    type Result<T> = std::result::Result<T, ParseError>;
+
+    fn error(source: &str, span: Range<usize>, message: String) -> ParseError {
+        ParseError {
+            message,
+            hint: None,
+            span,
+            source: source.to_string(),
+        }
+    }
+
+    fn tokenize(s: &str) -> Result<Vec<(Token, Range<usize>)>> {
+        let mut tokens = vec![];
+        let mut chars = s.char_indices().peekable();
+        while let Some((start, ch)) = chars.next() {
+            let token = match ch {
+                ' ' | '\t' => continue,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '0'..='9' => {
+                    let mut n = Some(ch as u64 - '0' as u64);
+                    while let Some(digit) = chars.peek().and_then(|(_, ch)| ch.to_digit(10)) {
+                        chars.next();
+                        n = n
+                            .and_then(|n| n.checked_mul(10))
+                            .and_then(|n| n.checked_add(digit as u64));
+                    }
+                    let end = chars.peek().map_or(s.len(), |(end, _)| *end);
+                    let n = n.ok_or_else(|| error(s, start..end, "Number too large".into()))?;
+                    tokens.push((Token::Number(n), start..end));
+                    continue;
+                }
+                _ if ch.is_ascii_alphabetic() || ch == '_' => {
+                    while let Some((_, ch)) = chars.peek() {
+                        if !ch.is_ascii_alphanumeric() && *ch != '_' {
+                            break;
                         }
+                        chars.next();
                     }
+                    let end = chars.peek().map_or(s.len(), |(end, _)| *end);
+                    tokens.push((Token::Identifier, start..end));
+                    continue;
                 }
+                _ if ch.is_ascii_punctuation() => Token::Operator(ch),
                 _ => {
-                    return Err(Error::General(format!(
-                        "Expected number or opening parenthesis but got {:?}",
-                        t1
-                    )))
+                    return Err(error(
+                        s,
+                        start..start + ch.len_utf8(),
+                        format!("Unexpected character {:?}", ch),
+                    ))
                 }
-            }
+            };
+            tokens.push((token, start..start + ch.len_utf8()));
         }
+        Ok(tokens)
     }
 
-    fn parse_expression<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        let mut expr = parse_literal_or_nested(tokens)?;
-        loop {
-            let next = tokens.peek();
-            match next {
-                Some(' ') => {
-                    tokens.next();
-                    continue;
+    struct Parser<'a> {
+        source: &'a str,
+        table: &'a OperatorTable,
+        tokens: Vec<(Token, Range<usize>)>,
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn peek(&self) -> Option<Token> {
+            self.tokens.get(self.pos).map(|(token, _)| *token)
+        }
+
+        fn next(&mut self) -> Option<Token> {
+            let token = self.peek();
+            self.pos += 1;
+            token
+        }
+
+        /// Error pointing at the token at `pos`, or just past the end of the input.
+        fn error_at(&self, pos: usize, message: String) -> ParseError {
+            let span = match self.tokens.get(pos) {
+                Some((_, span)) => span.clone(),
+                None => self.source.len()..self.source.len(),
+            };
+            error(self.source, span, message)
+        }
+
+        fn literal(&self, magnitude: u64, negative: bool) -> Result<Expression> {
+            let n = if negative {
+                -(magnitude as i128)
+            } else {
+                magnitude as i128
+            };
+            if n < i64::MIN as i128 || n > i64::MAX as i128 {
+                return Err(self.error_at(self.pos - 1, format!("Number {} out of range", n)));
+            }
+            Ok(Expression::Literal(n as i64))
+        }
+
+        fn parse_prefix(&mut self) -> Result<Expression> {
+            let t1 = self.next();
+            match t1 {
+                Some(Token::Number(n)) => self.literal(n, false),
+                Some(Token::Identifier) => {
+                    let span = self.tokens[self.pos - 1].1.clone();
+                    Ok(Expression::Variable(self.source[span].to_string()))
                 }
-                Some('+') => {
-                    tokens.next();
-                    expr =
-                        Expression::Add(Box::new(expr), Box::new(parse_literal_or_nested(tokens)?))
+                Some(Token::Operator('-')) => {
+                    if let Some(Token::Number(n)) = self.peek() {
+                        self.next();
+                        self.literal(n, true)
+                    } else {
+                        Ok(Expression::Neg(Box::new(self.parse_prefix()?)))
+                    }
                 }
-                Some('*') => {
-                    tokens.next();
-                    expr =
-                        Expression::Mul(Box::new(expr), Box::new(parse_literal_or_nested(tokens)?))
+                Some(Token::Open) => {
+                    let open = self.pos - 1;
+                    let res = self.parse_expression(0)?;
+                    match self.next() {
+                        Some(Token::Close) => Ok(res),
+                        t2 => {
+                            let got = t2.map_or("EOF".to_string(), |t2| t2.to_string());
+                            let mut e = self
+                                .error_at(self.pos - 1, format!("Expected ')' but got {}", got));
+                            let column = self.source[..self.tokens[open].1.start].chars().count();
+                            e.hint =
+                                Some(format!("the '(' at column {} is never closed", column + 1));
+                            Err(e)
+                        }
+                    }
                 }
-                None | Some(')') => {
-                    break;
+                _ => {
+                    let got = t1.map_or("EOF".to_string(), |t1| t1.to_string());
+                    let mut e = self.error_at(
+                        self.pos - 1,
+                        format!(
+                            "Expected number, variable or opening parenthesis but got {}",
+                            got
+                        ),
+                    );
+                    let previous = self.pos.checked_sub(2).and_then(|pos| self.tokens.get(pos));
+                    if let Some((Token::Operator(_), _)) = previous {
+                        e.hint = Some("two operators in a row, is an operand missing?".into());
+                    }
+                    Err(e)
                 }
-                Some(other) => {
-                    return Err(Error::General(format!(
-                        "Expected operator or ')' but got '{}",
-                        other
-                    )))
+            }
+        }
+
+        /// Precedence climbing, only consumes operators binding at least as tight as `min_precedence`.
+        /// Wider than the precedences themselves, left associative operators at 255 still need one more.
+        fn parse_expression(&mut self, min_precedence: u16) -> Result<Expression> {
+            let mut expr = self.parse_prefix()?;
+            loop {
+                match self.peek() {
+                    None | Some(Token::Close) => {
+                        break;
+                    }
+                    Some(Token::Operator(symbol)) => {
+                        let op = self.table.get(symbol).ok_or_else(|| {
+                            let mut e =
+                                self.error_at(self.pos, format!("Unknown operator '{}'", symbol));
+                            let known = self
+                                .table
+                                .operators
+                                .iter()
+                                .map(|op| op.operation.symbol().to_string())
+                                .collect::<Vec<_>>();
+                            e.hint = Some(format!("known operators are {}", known.join(" ")));
+                            e
+                        })?;
+                        if u16::from(op.precedence) < min_precedence {
+                            break;
+                        }
+                        self.next();
+                        let next_precedence = match op.associativity {
+                            Associativity::Left => u16::from(op.precedence) + 1,
+                            Associativity::Right => u16::from(op.precedence),
+                        };
+                        let right = self.parse_expression(next_precedence)?;
+                        expr = op.operation.build(expr, right);
+                    }
+                    Some(other) => {
+                        let mut e = self.error_at(
+                            self.pos,
+                            format!("Expected operator or ')' but got {}", other),
+                        );
+                        e.hint = Some("two operands in a row, is an operator missing?".into());
+                        return Err(e);
+                    }
                 }
             }
+            Ok(expr)
         }
-        Ok(expr)
     }
 
-    pub(super) fn parse<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        let expr = parse_expression(tokens)?;
-        let next = tokens.peek();
-        match next {
+    pub(super) fn parse(s: &str, table: &OperatorTable) -> Result<Expression> {
+        let mut parser = Parser {
+            source: s,
+            table,
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.parse_expression(0)?;
+        match parser.peek() {
             None => Ok(expr),
-            Some(other) => return Err(Error::General(format!("Expected EOF but got '{}", other))),
+            Some(other) => {
+                let mut e = parser.error_at(parser.pos, format!("Expected EOF but got {}", other));
+                if other == Token::Close {
+                    e.hint = Some("unmatched ')'".into());
+                }
+                Err(e)
+            }
         }
     }
 }
 
-mod part2 {
-    use super::Error;
+mod printer {
+    use super::Associativity;
     use super::Expression;
-    use super::Result;
+    use super::OperatorTable;
+    use std::fmt::{Display, Formatter, Write};
 
-    fn parse_literal_or_nested<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        loop {
-            let t1 = tokens
-                .next()
-                .ok_or_else(|| Error::General("Unexpected EOF".into()))?;
-            match t1 {
-                ' ' => continue,
-                '0'..='9' => return Ok(Expression::Literal(t1 as i64 - '0' as i64)),
-                '(' => {
-                    let res = parse_multiplication(tokens)?;
-                    loop {
-                        let t2 = tokens
-                            .next()
-                            .ok_or_else(|| Error::General("Expected ')' but got EOF".into()))?;
-                        match t2 {
-                            ' ' => continue,
-                            ')' => return Ok(res),
-                            _ => {
-                                return Err(Error::General(format!(
-                                    "Expected ')' but got {:?}",
-                                    t2
-                                )))
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    return Err(Error::General(format!(
-                        "Expected number or opening parenthesis but got {:?}",
-                        t1
-                    )))
+    /// Prints an expression with only the parentheses needed to parse it back
+    /// using the same operator table.
+    pub(super) struct Pretty<'a> {
+        expr: &'a Expression,
+        table: &'a OperatorTable,
+    }
+
+    impl<'a> Pretty<'a> {
+        pub(super) fn new(expr: &'a Expression, table: &'a OperatorTable) -> Self {
+            Self { expr, table }
+        }
+
+        fn nested(&self, expr: &'a Expression) -> Self {
+            Self::new(expr, self.table)
+        }
+
+        /// Precedence and associativity of the top level operator, `None` for literals and negation.
+        fn binding(&self, expr: &Expression) -> Option<(u8, Associativity)> {
+            expr.operation().map(|operation| {
+                self.table
+                    .operators
+                    .iter()
+                    .find(|op| op.operation == operation)
+                    // not parseable anyway, so parenthesize everywhere
+                    .map_or((0, Associativity::Left), |op| {
+                        (op.precedence, op.associativity)
+                    })
+            })
+        }
+
+        fn needs_parens(&self, child: &Expression, is_right: bool) -> bool {
+            let (precedence, associativity) = match self.binding(self.expr) {
+                None => return child.operation().is_some() || child.is_literal(),
+                Some(binding) => binding,
+            };
+            match self.binding(child) {
+                None => false,
+                Some((child_precedence, child_associativity)) => {
+                    let same_side = if is_right {
+                        Associativity::Right
+                    } else {
+                        Associativity::Left
+                    };
+                    child_precedence < precedence
+                        || (child_precedence == precedence
+                            && (associativity != same_side || child_associativity != same_side))
                 }
             }
         }
+
+        fn fmt_child(
+            &self,
+            child: &'a Expression,
+            is_right: bool,
+            f: &mut Formatter<'_>,
+        ) -> std::fmt::Result {
+            if self.needs_parens(child, is_right) {
+                f.write_fmt(format_args!("({})", self.nested(child)))
+            } else {
+                f.write_fmt(format_args!("{}", self.nested(child)))
+            }
+        }
     }
 
-    fn parse_addition<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        let mut expr = parse_literal_or_nested(tokens)?;
-        loop {
-            let next = tokens.peek();
-            match next {
-                Some(' ') => {
-                    tokens.next();
-                    continue;
-                }
-                Some('+') => {
-                    tokens.next();
-                    expr =
-                        Expression::Add(Box::new(expr), Box::new(parse_literal_or_nested(tokens)?))
+    impl<'a> Display for Pretty<'a> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self.expr {
+                Expression::Literal(n) => f.write_fmt(format_args!("{}", n)),
+                Expression::Variable(name) => f.write_str(name),
+                Expression::Neg(inner) => {
+                    f.write_char('-')?;
+                    self.fmt_child(inner, true, f)
                 }
-                None | Some(')') | Some('*') => {
-                    break;
+                Expression::Add(left, right)
+                | Expression::Sub(left, right)
+                | Expression::Mul(left, right)
+                | Expression::Div(left, right)
+                | Expression::Mod(left, right) => {
+                    self.fmt_child(left, false, f)?;
+                    f.write_fmt(format_args!(
+                        " {} ",
+                        self.expr.operation().unwrap().symbol()
+                    ))?;
+                    self.fmt_child(right, true, f)
                 }
-                Some(other) => {
-                    return Err(Error::General(format!(
-                        "Expected operator or ')' but got '{}",
-                        other
-                    )))
+            }
+        }
+    }
+}
+
+mod symbolic {
+    use super::Error;
+    use super::Expression;
+    use super::Operation;
+    use super::Result;
+    use std::collections::BTreeMap;
+
+    /// Product of atoms raised to their exponents, where atoms are variables or subexpressions
+    /// that can not be expanded, like a division by a variable.
+    type Monomial = BTreeMap<Expression, u32>;
+
+    /// Sum of monomials times their coefficients, the empty monomial is the constant term.
+    /// Zero coefficients are never stored.
+    type Polynomial = BTreeMap<Monomial, i64>;
+
+    fn overflow(expr: &Expression) -> Error {
+        Error::General(format!("Overflow while simplifying {}", expr))
+    }
+
+    fn constant(n: i64) -> Polynomial {
+        let mut polynomial = Polynomial::new();
+        if n != 0 {
+            polynomial.insert(Monomial::new(), n);
+        }
+        polynomial
+    }
+
+    fn as_constant(polynomial: &Polynomial) -> Option<i64> {
+        match polynomial.iter().next() {
+            None => Some(0),
+            Some((monomial, n)) if polynomial.len() == 1 && monomial.is_empty() => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn atom(expr: Expression) -> Polynomial {
+        let mut monomial = Monomial::new();
+        monomial.insert(expr, 1);
+        let mut polynomial = Polynomial::new();
+        polynomial.insert(monomial, 1);
+        polynomial
+    }
+
+    fn add_term(polynomial: &mut Polynomial, monomial: Monomial, n: i64) -> Option<()> {
+        let sum = polynomial
+            .get(&monomial)
+            .copied()
+            .unwrap_or(0)
+            .checked_add(n)?;
+        if sum == 0 {
+            polynomial.remove(&monomial);
+        } else {
+            polynomial.insert(monomial, sum);
+        }
+        Some(())
+    }
+
+    fn add(mut left: Polynomial, right: Polynomial, sign: i64) -> Option<Polynomial> {
+        for (monomial, n) in right {
+            add_term(&mut left, monomial, n.checked_mul(sign)?)?;
+        }
+        Some(left)
+    }
+
+    fn mul(left: &Polynomial, right: &Polynomial) -> Option<Polynomial> {
+        let mut product = Polynomial::new();
+        for (m1, n1) in left.iter() {
+            for (m2, n2) in right.iter() {
+                let mut monomial = m1.clone();
+                for (atom, exponent) in m2.iter() {
+                    let sum = monomial
+                        .get(atom)
+                        .copied()
+                        .unwrap_or(0)
+                        .checked_add(*exponent)?;
+                    monomial.insert(atom.clone(), sum);
                 }
+                add_term(&mut product, monomial, n1.checked_mul(*n2)?)?;
             }
         }
-        Ok(expr)
+        Some(product)
     }
 
-    fn parse_multiplication<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        let mut expr = parse_addition(tokens)?;
-        loop {
-            let next = tokens.peek();
-            match next {
-                Some(' ') => {
-                    tokens.next();
-                    continue;
+    fn to_polynomial(expr: &Expression) -> Result<Polynomial> {
+        match expr {
+            Expression::Literal(n) => Ok(constant(*n)),
+            Expression::Variable(_) => Ok(atom(expr.clone())),
+            Expression::Neg(inner) => {
+                add(Polynomial::new(), to_polynomial(inner)?, -1).ok_or_else(|| overflow(expr))
+            }
+            Expression::Add(left, right) | Expression::Sub(left, right) => {
+                let sign = if let Expression::Add(_, _) = expr {
+                    1
+                } else {
+                    -1
+                };
+                add(to_polynomial(left)?, to_polynomial(right)?, sign).ok_or_else(|| overflow(expr))
+            }
+            Expression::Mul(left, right) => {
+                mul(&to_polynomial(left)?, &to_polynomial(right)?).ok_or_else(|| overflow(expr))
+            }
+            Expression::Div(left, right) | Expression::Mod(left, right) => {
+                let operation = expr.operation().unwrap();
+                let left = to_polynomial(left)?;
+                let right = to_polynomial(right)?;
+                match (as_constant(&left), as_constant(&right), operation) {
+                    (Some(a), Some(b), _) => Ok(constant(operation.apply(a, b)?)),
+                    (_, Some(1), Operation::Div) => Ok(left),
+                    (_, Some(1), Operation::Mod) => Ok(Polynomial::new()),
+                    _ => Ok(atom(
+                        operation.build(from_polynomial(&left), from_polynomial(&right)),
+                    )),
                 }
-                Some('*') => {
-                    tokens.next();
-                    expr = Expression::Mul(Box::new(expr), Box::new(parse_addition(tokens)?))
+            }
+        }
+    }
+
+    fn degree(monomial: &Monomial) -> u64 {
+        monomial.values().map(|exponent| *exponent as u64).sum()
+    }
+
+    fn from_polynomial(polynomial: &Polynomial) -> Expression {
+        let mut terms: Vec<(&Monomial, i64)> = polynomial
+            .iter()
+            .map(|(monomial, n)| (monomial, *n))
+            .collect();
+        // highest degree first, the constant last
+        terms.sort_by_key(|(monomial, _)| std::cmp::Reverse(degree(monomial)));
+
+        let mut sum: Option<Expression> = None;
+        for (monomial, n) in terms {
+            // i64::MIN has no positive counterpart, keep it as a negative literal
+            let negative = n < 0 && n != i64::MIN;
+            let magnitude = if negative { -n } else { n };
+
+            let mut factors: Vec<Expression> = vec![];
+            if magnitude != 1 || monomial.is_empty() {
+                factors.push(Expression::Literal(magnitude));
+            }
+            for (atom, exponent) in monomial.iter() {
+                (0..*exponent).for_each(|_| factors.push(atom.clone()));
+            }
+            let term = factors
+                .into_iter()
+                .fold(None, |product, factor| match product {
+                    None => Some(factor),
+                    Some(product) => Some(Operation::Mul.build(product, factor)),
+                })
+                .unwrap();
+
+            sum = Some(match (sum, negative) {
+                (None, false) => term,
+                (None, true) => match term {
+                    Expression::Literal(n) => Expression::Literal(-n),
+                    term => Expression::Neg(Box::new(term)),
+                },
+                (Some(sum), false) => Operation::Add.build(sum, term),
+                (Some(sum), true) => Operation::Sub.build(sum, term),
+            });
+        }
+
+        sum.unwrap_or(Expression::Literal(0))
+    }
+
+    /// Folds constants, removes identities and collects like terms by expanding into a polynomial.
+    pub(super) fn simplify(expr: &Expression) -> Result<Expression> {
+        Ok(from_polynomial(&to_polynomial(expr)?))
+    }
+
+    fn derivative(expr: &Expression, variable: &str) -> Result<Expression> {
+        match expr {
+            Expression::Literal(_) => Ok(Expression::Literal(0)),
+            Expression::Variable(name) => Ok(Expression::Literal((name == variable) as i64)),
+            Expression::Neg(inner) => Ok(Expression::Neg(Box::new(derivative(inner, variable)?))),
+            Expression::Add(left, right) | Expression::Sub(left, right) => Ok(expr
+                .operation()
+                .unwrap()
+                .build(derivative(left, variable)?, derivative(right, variable)?)),
+            Expression::Mul(left, right) => Ok(Operation::Add.build(
+                Operation::Mul.build(derivative(left, variable)?, right.as_ref().clone()),
+                Operation::Mul.build(left.as_ref().clone(), derivative(right, variable)?),
+            )),
+            Expression::Div(_, _) | Expression::Mod(_, _) => {
+                if expr.contains_variable(variable) {
+                    Err(Error::General(format!(
+                        "Can not differentiate integer division {} with respect to {}",
+                        expr, variable
+                    )))
+                } else {
+                    Ok(Expression::Literal(0))
                 }
-                None | Some(')') | Some('+') => {
-                    break;
+            }
+        }
+    }
+
+    /// Symbolic derivative with respect to `variable`, in simplified form.
+    pub(super) fn differentiate(expr: &Expression, variable: &str) -> Result<Expression> {
+        simplify(&derivative(expr, variable)?)
+    }
+}
+
+mod bytecode {
+    use super::Error;
+    use super::Expression;
+    use super::Operation;
+    use super::Result;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Op {
+        Push(i64),
+        Neg,
+        Binary(Operation),
+    }
+
+    fn compile(expr: &Expression, ops: &mut Vec<Op>) -> Result<()> {
+        match expr {
+            Expression::Literal(n) => ops.push(Op::Push(*n)),
+            Expression::Variable(name) => {
+                return Err(Error::General(format!(
+                    "Can not compile unbound variable {}",
+                    name
+                )))
+            }
+            Expression::Neg(inner) => {
+                compile(inner, ops)?;
+                ops.push(Op::Neg);
+            }
+            Expression::Add(left, right)
+            | Expression::Sub(left, right)
+            | Expression::Mul(left, right)
+            | Expression::Div(left, right)
+            | Expression::Mod(left, right) => {
+                compile(left, ops)?;
+                compile(right, ops)?;
+                ops.push(Op::Binary(expr.operation().unwrap()));
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(ops: &[Op], stack: &mut Vec<i64>) -> Result<i64> {
+        stack.clear();
+        for op in ops.iter() {
+            match op {
+                Op::Push(n) => stack.push(*n),
+                Op::Neg => {
+                    let n = stack.pop().unwrap();
+                    stack.push(Operation::Sub.apply(0, n)?);
                 }
-                Some(other) => {
-                    return Err(Error::General(format!(
-                        "Expected operator or ')' but got '{}",
-                        other
-                    )))
+                Op::Binary(operation) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(operation.apply(left, right)?);
                 }
             }
         }
-        Ok(expr)
+        Ok(stack.pop().unwrap())
     }
 
-    pub(super) fn parse<I: Iterator<Item = char>>(
-        tokens: &mut std::iter::Peekable<I>,
-    ) -> Result<Expression> {
-        let expr = parse_multiplication(tokens)?;
-        let next = tokens.peek();
-        match next {
-            None => Ok(expr),
-            Some(other) => return Err(Error::General(format!("Expected EOF but got '{}", other))),
+    /// Many expressions compiled to postfix code in a single flat buffer.
+    #[derive(Debug, Clone, Default)]
+    pub(super) struct Batch {
+        ops: Vec<Op>,
+        ends: Vec<usize>,
+    }
+
+    impl Batch {
+        pub(super) fn push(&mut self, expr: &Expression) -> Result<()> {
+            let start = self.ops.len();
+            if let Err(e) = compile(expr, &mut self.ops) {
+                self.ops.truncate(start);
+                return Err(e);
+            }
+            self.ends.push(self.ops.len());
+            Ok(())
+        }
+
+        pub(super) fn evaluate(&self) -> impl Iterator<Item = Result<i64>> + '_ {
+            let mut stack = vec![];
+            let starts = std::iter::once(0).chain(self.ends.iter().copied());
+            starts
+                .zip(self.ends.iter())
+                .map(move |(start, end)| execute(&self.ops[start..*end], &mut stack))
         }
     }
 }
 
+fn random_expression(rng: &mut XorShift, depth: u32) -> Expression {
+    let choice = if depth == 0 { 0 } else { rng.next_u64() % 8 };
+    let mut nested = || Box::new(random_expression(rng, depth - 1));
+    match choice {
+        0 | 1 => Expression::Literal(rng.next_u64() as i64 % 100),
+        2 => Expression::Neg(nested()),
+        3 => Expression::Add(nested(), nested()),
+        4 => Expression::Sub(nested(), nested()),
+        5 => Expression::Mul(nested(), nested()),
+        6 => Expression::Div(nested(), nested()),
+        _ => Expression::Mod(nested(), nested()),
+    }
+}
+
 fn main() -> Result<()> {
     let lines: Vec<String> = read_file("data/18.txt")?;
 
-    let mut tokens = "8 * 7 + 6".chars().peekable();
-    let ast = part1::parse(&mut tokens)?;
+    let part1_table = OperatorTable::part1();
+    let part2_table = OperatorTable::part2();
+
+    let ast = parser::parse("8 * 7 + 6", &part1_table)?;
     println!("{}", &ast);
-    println!("{}", &ast.evaluate());
+    println!("{}", ast.pretty(&part1_table));
+    println!("{}", ast.pretty(&part2_table));
+    println!("{}", &ast.evaluate()?);
 
-    let mut tokens = "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"
-        .chars()
-        .peekable();
-    let ast = part2::parse(&mut tokens)?;
+    let ast = parser::parse(
+        "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+        &part2_table,
+    )?;
     println!("{}", &ast);
-    println!("{}", &ast.evaluate());
+    println!("{}", &ast.evaluate()?);
 
-    let part1 = lines.iter().try_fold(0_i64, |mut sum, l| -> Result<i64> {
-        let mut tokens = l.chars().peekable();
-        let expr = part1::parse(&mut tokens)
-            .map_err(|e| Error::General(format!("Could not parse line '{}': {}", l, e)))?;
-        sum += expr.evaluate();
-        Ok(sum)
-    })?;
+    // multiplication binding tighter than addition, like regular math
+    let table = OperatorTable::from_str("+ 1 left, - 1 left, * 2 left, / 2 left, % 2 left")?;
+    let ast = parser::parse("12 * -3 + 40 / (5 - 7) - -(6 % 4)", &table)?;
+    println!("{}", &ast);
+    println!("{}", ast.pretty(&table));
+    println!("{}", &ast.evaluate()?);
+
+    let sum_with = |table: &OperatorTable| {
+        lines.iter().try_fold(0_i64, |sum, l| -> Result<i64> {
+            let expr = parser::parse(l, table)?;
+            sum.checked_add(expr.evaluate()?)
+                .ok_or_else(|| Error::General("Overflow in sum".into()))
+        })
+    };
+
+    let part1 = sum_with(&part1_table)?;
 
     println!("{}", part1);
 
-    let part2 = lines.iter().try_fold(0_i64, |mut sum, l| -> Result<i64> {
-        let mut tokens = l.chars().peekable();
-        let expr = part2::parse(&mut tokens)
-            .map_err(|e| Error::General(format!("Could not parse line '{}': {}", l, e)))?;
-        sum += expr.evaluate();
-        Ok(sum)
-    })?;
+    let part2 = sum_with(&part2_table)?;
 
     println!("{}", part2);
 
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("bench") => bench(&mut XorShift::new(0x2545_f491_4f6c_dd1d), &part2_table)?,
+        Some("simplify") => {
+            let ast = parser::parse(&args.next().unwrap_or_default(), &table)?;
+            println!("{}", symbolic::simplify(&ast)?.pretty(&table));
+        }
+        Some("differentiate") => {
+            let ast = parser::parse(&args.next().unwrap_or_default(), &table)?;
+            let variable = args.next().unwrap_or_else(|| "x".to_string());
+            println!(
+                "{}",
+                symbolic::differentiate(&ast, &variable)?.pretty(&table)
+            );
+        }
+        _ => {}
+    }
+
     Ok(())
 }
+
+/// Compares tree walking and bytecode evaluation on a generated file of a million expressions.
+fn bench(rng: &mut XorShift, table: &OperatorTable) -> Result<()> {
+    let path = std::env::temp_dir().join("a18_bench.txt");
+    let mut generated = String::new();
+    for _ in 0..1_000_000 {
+        generated.push_str(&random_expression(rng, 4).pretty(table).to_string());
+        generated.push('\n');
+    }
+    std::fs::write(&path, generated)?;
+
+    let start = Instant::now();
+    let lines: Vec<String> = read_file(path.to_str().unwrap())?;
+    let expressions = lines
+        .iter()
+        .map(|l| parser::parse(l, table))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    println!("parse:    {:?}", start.elapsed());
+
+    // errors like division by zero are part of the workload, so only compare the results
+    let checksum = |results: &mut dyn Iterator<Item = Result<i64>>| {
+        results.fold((0_i64, 0_usize), |(sum, errors), result| match result {
+            Ok(n) => (sum.wrapping_add(n), errors),
+            Err(_) => (sum, errors + 1),
+        })
+    };
+
+    let start = Instant::now();
+    let tree = checksum(&mut expressions.iter().map(|expr| expr.evaluate()));
+    println!("tree:     {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut batch = bytecode::Batch::default();
+    expressions.iter().try_for_each(|expr| batch.push(expr))?;
+    println!("compile:  {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let compiled = checksum(&mut batch.evaluate());
+    println!("bytecode: {:?}", start.elapsed());
+
+    println!("{:?} {:?}", tree, compiled);
+    if tree != compiled {
+        return Err(Error::General("Bytecode results differ".into()));
+    }
+
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highest_precedence_left_associative() {
+        let table = OperatorTable::from_str("+ 255 left, * 1 left").unwrap();
+        let ast = parser::parse("1 + 2 + 3 * 4", &table).unwrap();
+        assert_eq!(ast.evaluate().unwrap(), 24);
+        assert_eq!(ast.pretty(&table).to_string(), "1 + 2 + 3 * 4");
+    }
+
+    fn math_table() -> OperatorTable {
+        OperatorTable::from_str("+ 1 left, - 1 left, * 2 left, / 2 left, % 2 left").unwrap()
+    }
+
+    fn evaluate(s: &str) -> Result<i64> {
+        parser::parse(s, &math_table())?.evaluate()
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(evaluate("12 * -3 + 40 / (5 - 7) - -(6 % 4)").unwrap(), -54);
+        assert_eq!(evaluate("-9223372036854775808").unwrap(), i64::MIN);
+        assert_eq!(
+            evaluate("1 / (2 - 2)").unwrap_err().to_string(),
+            "General: Division by zero in 1/0"
+        );
+        assert_eq!(
+            evaluate("7 % 0").unwrap_err().to_string(),
+            "General: Division by zero in 7%0"
+        );
+        assert_eq!(
+            evaluate("9223372036854775807 + 1").unwrap_err().to_string(),
+            "General: Overflow in 9223372036854775807+1"
+        );
+        assert_eq!(
+            evaluate("-(-9223372036854775808)").unwrap_err().to_string(),
+            "General: Overflow in --9223372036854775808"
+        );
+        assert_eq!(
+            evaluate("-9223372036854775808 / -1")
+                .unwrap_err()
+                .to_string(),
+            "General: Overflow in -9223372036854775808/-1"
+        );
+        assert!(evaluate("9223372036854775808").is_err());
+        assert!(evaluate("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn invalid_input_diagnostics() {
+        let table = math_table();
+        let diagnostics: Vec<String> = ["8 * (7 + 6", "8 * * 6", "8 7", "8 ^ 7", "(8 + 7))"]
+            .iter()
+            .map(|invalid| parser::parse(invalid, &table).unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "Expected ')' but got EOF\n  8 * (7 + 6\n            ^\n\
+                 hint: the '(' at column 5 is never closed",
+                "Expected number, variable or opening parenthesis but got '*'\n  8 * * 6\n      ^\n\
+                 hint: two operators in a row, is an operand missing?",
+                "Expected operator or ')' but got 7\n  8 7\n    ^\n\
+                 hint: two operands in a row, is an operator missing?",
+                "Unknown operator '^'\n  8 ^ 7\n    ^\nhint: known operators are + - * / %",
+                "Expected EOF but got ')'\n  (8 + 7))\n         ^\nhint: unmatched ')'",
+            ]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let table = math_table();
+        let ast = parser::parse("x * (y + 2)", &table).unwrap();
+        let env: HashMap<String, i64> = vec![("x".to_string(), 3), ("y".to_string(), 4)]
+            .into_iter()
+            .collect();
+        assert_eq!(ast.evaluate_with(&env).unwrap(), 18);
+        assert_eq!(
+            ast.evaluate().unwrap_err().to_string(),
+            "General: Unbound variable x"
+        );
+    }
+
+    #[test]
+    fn simplify_collects_like_terms() {
+        let table = math_table();
+        let simplified = |s: &str| {
+            let ast = parser::parse(s, &table).unwrap();
+            symbolic::simplify(&ast).unwrap().pretty(&table).to_string()
+        };
+        assert_eq!(
+            simplified("(x + 1) * (x - 1) + x * 0 + 1 * y - y"),
+            "x * x - 1"
+        );
+        assert_eq!(simplified("4 / 2 * z / 1 + z"), "3 * z");
+    }
+
+    #[test]
+    fn differentiate() {
+        let table = math_table();
+        let ast = parser::parse("3 * x * x * y + 2 * x * y - (y + 5) * x + 7", &table).unwrap();
+        let derivative = symbolic::differentiate(&ast, "x").unwrap();
+        assert_eq!(derivative.pretty(&table).to_string(), "6 * x * y + y - 5");
+        assert_eq!(
+            symbolic::differentiate(&ast, "z").unwrap(),
+            Expression::Literal(0)
+        );
+
+        let ast = parser::parse("x / 2 + y / 3", &table).unwrap();
+        assert_eq!(
+            symbolic::differentiate(&ast, "x").unwrap_err().to_string(),
+            "General: Can not differentiate integer division (x/2) with respect to x"
+        );
+        assert_eq!(
+            symbolic::differentiate(&ast, "z").unwrap(),
+            Expression::Literal(0)
+        );
+    }
+
+    #[test]
+    fn caret_keeps_tabs() {
+        let e = parser::parse("1 +\t* 2", &OperatorTable::part1()).unwrap_err();
+        let caret = e.to_string().lines().nth(2).unwrap().to_string();
+        assert_eq!(caret, "     \t^");
+    }
+
+    #[test]
+    fn pretty_print_round_trip() {
+        let mut rng = XorShift::new(0x2545_f491_4f6c_dd1d);
+        let right_table =
+            OperatorTable::from_str("+ 2 right, - 2 left, * 1 right, / 1 left, % 1 left").unwrap();
+        for table in &[OperatorTable::part1(), OperatorTable::part2(), right_table] {
+            for _ in 0..1000 {
+                let expr = random_expression(&mut rng, 5);
+                let printed = expr.pretty(table).to_string();
+                assert_eq!(parser::parse(&printed, table).unwrap(), expr, "{}", printed);
+            }
+        }
+    }
+
+    #[test]
+    fn bytecode_matches_tree_walking() {
+        let lines: Vec<String> = read_file("data/18.txt").unwrap();
+        let table = OperatorTable::part2();
+        let mut batch = bytecode::Batch::default();
+        let mut tree = 0_i64;
+        for l in &lines {
+            let expr = parser::parse(l, &table).unwrap();
+            tree += expr.evaluate().unwrap();
+            batch.push(&expr).unwrap();
+        }
+        let compiled: i64 = batch.evaluate().map(|result| result.unwrap()).sum();
+        assert_eq!(compiled, tree);
+    }
+}
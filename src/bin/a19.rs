@@ -1,7 +1,9 @@
 use adventofcode2020::prelude::*;
 use regex_automata::{Regex, RegexBuilder, DFA};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Write};
 use std::str::FromStr;
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 struct Rule {
@@ -11,7 +13,7 @@ struct Rule {
 
 #[derive(Debug, Clone)]
 enum Pattern {
-    Char(char),
+    Literal(String),
     Sequence(Vec<u64>),
     Alternatives(Vec<Vec<u64>>),
 }
@@ -20,7 +22,7 @@ impl FromStr for Rule {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let mut split = s.split(": ");
+        let mut split = s.splitn(2, ": ");
         let id = split
             .next()
             .ok_or_else(|| Error::General("Missing id".into()))?
@@ -33,19 +35,52 @@ impl FromStr for Rule {
     }
 }
 
+/// Parses the rest of a quoted literal after the opening quote, supporting the escapes
+/// `\"`, `\\`, `\n` and `\t`.
+fn parse_literal(quoted: &str) -> Result<String> {
+    let mut literal = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next() {
+            None => return Err(Error::General(format!("Unterminated literal \"{}", quoted))),
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+                Some('"') => literal.push('"'),
+                Some('\\') => literal.push('\\'),
+                Some('n') => literal.push('\n'),
+                Some('t') => literal.push('\t'),
+                Some(other) => {
+                    return Err(Error::General(format!(
+                        "Invalid escape \\{} in literal \"{}",
+                        other, quoted
+                    )))
+                }
+                None => return Err(Error::General(format!("Unterminated literal \"{}", quoted))),
+            },
+            Some(ch) => literal.push(ch),
+        }
+    }
+
+    let rest = chars.as_str();
+    if !rest.trim().is_empty() {
+        return Err(Error::General(format!(
+            "Unexpected '{}' after literal \"{}",
+            rest, quoted
+        )));
+    }
+    if literal.is_empty() {
+        return Err(Error::General("Empty literal".into()));
+    }
+
+    Ok(literal)
+}
+
 impl FromStr for Pattern {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        if let Some(first_char) = s
-            .strip_prefix('"')
-            .and_then(|s| s.strip_suffix('"'))
-            .and_then(|s| {
-                let mut chars = s.chars();
-                chars.next()
-            })
-        {
-            Ok(Pattern::Char(first_char))
+        if let Some(quoted) = s.strip_prefix('"') {
+            Ok(Pattern::Literal(parse_literal(quoted)?))
         } else {
             let mut alternatives: Vec<Vec<u64>> = s.split(" | ").try_fold(
                 vec![],
@@ -72,92 +107,2004 @@ impl FromStr for Pattern {
     }
 }
 
-fn append_sequence(seq: &[u64], rules: &HashMap<u64, Pattern>, output: &mut String) -> Result<()> {
-    seq.iter()
-        .try_for_each(|item| build_regex_recursive(*item, rules, output))?;
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Literal(literal) => {
+                f.write_char('"')?;
+                for ch in literal.chars() {
+                    match ch {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        ch => f.write_char(ch)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            _ => {
+                for (i, seq) in self.sequences().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" | ")?;
+                    }
+                    for (j, id) in seq.iter().enumerate() {
+                        if j > 0 {
+                            f.write_char(' ')?;
+                        }
+                        f.write_fmt(format_args!("{}", id))?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
-    Ok(())
+impl Pattern {
+    /// The alternative sequences of rule references, empty for terminals.
+    fn sequences(&self) -> Vec<&[u64]> {
+        match self {
+            Pattern::Literal(_) => vec![],
+            Pattern::Sequence(seq) => vec![seq.as_slice()],
+            Pattern::Alternatives(alternatives) => {
+                alternatives.iter().map(|seq| seq.as_slice()).collect()
+            }
+        }
+    }
 }
 
-fn append_pattern(
-    pattern: &Pattern,
-    rules: &HashMap<u64, Pattern>,
-    output: &mut String,
-) -> Result<()> {
-    match pattern {
-        Pattern::Char(ch) => {
-            output.push(*ch);
-        }
-        Pattern::Sequence(seq) => {
-            append_sequence(seq, rules, output)?;
-        }
-        Pattern::Alternatives(alternatives) => {
-            output.push('(');
-            alternatives.iter().try_for_each(|seq| -> Result<()> {
-                append_sequence(seq, rules, output)?;
-                output.push('|');
-                Ok(())
-            })?;
-            // remove last pipe
-            output.pop();
-            output.push(')');
+mod analysis {
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use std::collections::{BTreeSet, HashMap};
+    use std::fmt::{Display, Formatter};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(super) enum Class {
+        Finite,
+        /// only rules that recurse on themselves at the start or the end of their alternatives
+        Regular,
+        /// recursion through several rules, which might still be regular but is neither
+        /// analysed further nor compiled to a regex
+        MutuallyRecursive,
+        /// recursion in the middle of a sequence
+        ContextFree,
+    }
 
-            // optimize a bit for readability
-            if output.ends_with("(a|b)") || output.ends_with("(b|a)") {
-                output.truncate(output.len() - 5);
-                output.push('.');
+    #[derive(Debug, Clone)]
+    pub(super) struct Analysis {
+        pub(super) class: Class,
+        /// pairs of referencing rule and missing rule
+        pub(super) undefined: Vec<(u64, u64)>,
+        pub(super) unreachable: Vec<u64>,
+        /// groups of mutually recursive rules reachable from the start rule
+        pub(super) cycles: Vec<Vec<u64>>,
+        /// why the grammar is not regular
+        pub(super) problems: Vec<String>,
+    }
+
+    /// The alternatives of a rule that recurses on itself only at the edges of its sequences,
+    /// it then matches `(prefixes)* (bases) (suffixes)*`.
+    pub(super) struct Linear<'a> {
+        pub(super) prefixes: Vec<&'a [u64]>,
+        pub(super) bases: Vec<&'a [u64]>,
+        pub(super) suffixes: Vec<&'a [u64]>,
+    }
+
+    /// Splits a self recursive rule into its linear parts, `None` if the rule does not refer to itself.
+    pub(super) fn linear(
+        rule: u64,
+        pattern: &Pattern,
+    ) -> std::result::Result<Option<Linear>, String> {
+        let sequences = pattern.sequences();
+        if !sequences.iter().any(|seq| seq.contains(&rule)) {
+            return Ok(None);
+        }
+
+        let mut linear = Linear {
+            prefixes: vec![],
+            bases: vec![],
+            suffixes: vec![],
+        };
+        for seq in sequences {
+            let count = seq.iter().filter(|r| **r == rule).count();
+            match seq {
+                _ if count == 0 => linear.bases.push(seq),
+                // `8: 8` adds nothing to the language
+                [single] if *single == rule => {}
+                [prefix @ .., last] if count == 1 && *last == rule => linear.prefixes.push(prefix),
+                [first, suffix @ ..] if count == 1 && *first == rule => {
+                    linear.suffixes.push(suffix)
+                }
+                _ => {
+                    return Err(format!(
+                        "Rule {} recurses in the middle of {}",
+                        rule,
+                        seq.iter()
+                            .map(|r| r.to_string())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    ))
+                }
             }
-            if output.ends_with("(ba|aa)") {
-                output.truncate(output.len() - 7);
-                output.push_str(".a");
+        }
+
+        if linear.bases.is_empty() {
+            return Err(format!(
+                "Rule {} has no alternative without recursion",
+                rule
+            ));
+        }
+
+        Ok(Some(linear))
+    }
+
+    fn references(pattern: &Pattern) -> BTreeSet<u64> {
+        pattern.sequences().into_iter().flatten().copied().collect()
+    }
+
+    pub(super) fn analyze(rules: &HashMap<u64, Pattern>, start: u64) -> Analysis {
+        let mut ids: Vec<u64> = rules.keys().copied().collect();
+        ids.sort_unstable();
+
+        let undefined = ids
+            .iter()
+            .flat_map(|id| {
+                references(&rules[id])
+                    .into_iter()
+                    .filter(|r| !rules.contains_key(r))
+                    .map(move |r| (*id, r))
+            })
+            .collect();
+
+        // rules reachable in at least one step
+        let reach: HashMap<u64, BTreeSet<u64>> = ids
+            .iter()
+            .map(|id| {
+                let mut reached = BTreeSet::new();
+                let mut stack: Vec<u64> = references(&rules[id]).into_iter().collect();
+                while let Some(r) = stack.pop() {
+                    if reached.insert(r) {
+                        if let Some(pattern) = rules.get(&r) {
+                            stack.extend(references(pattern));
+                        }
+                    }
+                }
+                (*id, reached)
+            })
+            .collect();
+
+        let mut reachable = reach.get(&start).cloned().unwrap_or_default();
+        reachable.insert(start);
+        let unreachable = ids
+            .iter()
+            .filter(|id| !reachable.contains(id))
+            .copied()
+            .collect();
+
+        let mut cycles: Vec<Vec<u64>> = vec![];
+        for id in ids.iter().filter(|id| reachable.contains(id)) {
+            if reach[id].contains(id) && !cycles.iter().any(|cycle| cycle.contains(id)) {
+                cycles.push(
+                    reach[id]
+                        .iter()
+                        .filter(|other| reach.get(other).map_or(false, |r| r.contains(id)))
+                        .copied()
+                        .collect(),
+                );
             }
-            if output.ends_with("(ab|aa)") {
-                output.truncate(output.len() - 7);
-                output.push_str("a.");
+        }
+
+        let mut problems = vec![];
+        let mut context_free = false;
+        for cycle in cycles.iter() {
+            if let [rule] = cycle.as_slice() {
+                if let Err(problem) = linear(*rule, &rules[rule]) {
+                    problems.push(problem);
+                    context_free = true;
+                }
+            } else {
+                problems.push(format!(
+                    "Rules {} are mutually recursive",
+                    cycle
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
             }
-            if output.ends_with("(ab|bb)") {
-                output.truncate(output.len() - 7);
-                output.push_str(".b");
+        }
+
+        let class = if cycles.is_empty() {
+            Class::Finite
+        } else if context_free {
+            Class::ContextFree
+        } else if problems.is_empty() {
+            Class::Regular
+        } else {
+            Class::MutuallyRecursive
+        };
+
+        Analysis {
+            class,
+            undefined,
+            unreachable,
+            cycles,
+            problems,
+        }
+    }
+
+    impl Analysis {
+        /// Fails with the offending rules if the grammar can not be compiled to a regex.
+        pub(super) fn check_regular(&self) -> Result<()> {
+            // broken rules that the start rule never uses do not matter
+            let reachable_undefined = self
+                .undefined
+                .iter()
+                .find(|(rule, _missing)| !self.unreachable.contains(rule));
+            if let Some((rule, missing)) = reachable_undefined {
+                return Err(Error::General(format!(
+                    "Rule {} references undefined rule {}",
+                    rule, missing
+                )));
             }
-            if output.ends_with("(bb|ba)") {
-                output.truncate(output.len() - 7);
-                output.push_str("b.");
+            if self.class == Class::ContextFree || self.class == Class::MutuallyRecursive {
+                return Err(Error::General(format!(
+                    "Grammar is not regular: {}",
+                    self.problems.join("; ")
+                )));
             }
+            Ok(())
         }
-    };
+    }
 
-    Ok(())
+    impl Display for Analysis {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let list = |ids: &mut dyn Iterator<Item = String>| ids.collect::<Vec<_>>().join(" ");
+            f.write_fmt(format_args!("class: {:?}\n", self.class))?;
+            f.write_fmt(format_args!(
+                "undefined: {}\n",
+                list(&mut self.undefined.iter().map(|(r, m)| format!("{}->{}", r, m)))
+            ))?;
+            f.write_fmt(format_args!(
+                "unreachable: {}\n",
+                list(&mut self.unreachable.iter().map(|r| r.to_string()))
+            ))?;
+            f.write_fmt(format_args!(
+                "cycles: {}",
+                list(&mut self.cycles.iter().map(|cycle| format!("{:?}", cycle)))
+            ))?;
+            for problem in self.problems.iter() {
+                f.write_fmt(format_args!("\n{}", problem))?;
+            }
+            Ok(())
+        }
+    }
 }
 
-fn build_regex_recursive(
-    rule: u64,
-    rules: &HashMap<u64, Pattern>,
-    output: &mut String,
-) -> Result<()> {
-    match rules.get(&rule) {
-        None => Err(Error::General(format!("Could not find rule {}", rule))),
-        Some(pattern) => append_pattern(pattern, rules, output),
+mod regex_ast {
+    use super::analysis;
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::fmt::{Display, Formatter, Write};
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    pub(super) enum Node {
+        /// matches the empty string
+        Empty,
+        Class(BTreeSet<char>),
+        Concat(Vec<Node>),
+        Alt(Vec<Node>),
+        Star(Box<Node>),
+    }
+
+    impl Node {
+        fn char(ch: char) -> Self {
+            let mut set = BTreeSet::new();
+            set.insert(ch);
+            Node::Class(set)
+        }
+
+        fn into_sequence(self) -> Vec<Node> {
+            match self {
+                Node::Empty => vec![],
+                Node::Concat(nodes) => nodes,
+                node => vec![node],
+            }
+        }
+
+        fn concat(nodes: Vec<Node>) -> Self {
+            let mut nodes: Vec<Node> = nodes.into_iter().flat_map(Node::into_sequence).collect();
+            match nodes.len() {
+                0 => Node::Empty,
+                1 => nodes.pop().unwrap(),
+                _ => Node::Concat(nodes),
+            }
+        }
+
+        fn star(node: Node) -> Self {
+            match node {
+                Node::Empty => Node::Empty,
+                node @ Node::Star(_) => node,
+                node => Node::Star(Box::new(node)),
+            }
+        }
+
+        /// Builds an alternation, removing duplicates, factoring out common prefixes and suffixes
+        /// and merging single character alternatives into one class.
+        fn alt(nodes: Vec<Node>) -> Self {
+            let mut flattened = vec![];
+            for node in nodes {
+                match node {
+                    Node::Alt(nested) => flattened.extend(nested),
+                    node => flattened.push(node),
+                }
+            }
+            flattened.sort();
+            flattened.dedup();
+
+            let optional = flattened.contains(&Node::Empty);
+            flattened.retain(|node| *node != Node::Empty);
+
+            // group alternatives by their first element, `ab|ac` becomes `a(b|c)`
+            let mut groups: BTreeMap<Node, Vec<Node>> = BTreeMap::new();
+            for node in flattened {
+                let mut seq = node.into_sequence();
+                let first = seq.remove(0);
+                groups
+                    .entry(first)
+                    .or_insert_with(Vec::new)
+                    .push(Node::concat(seq));
+            }
+            let mut alternatives: Vec<Node> = groups
+                .into_iter()
+                .map(|(first, rests)| {
+                    if rests.len() == 1 {
+                        Node::concat(vec![first, rests.into_iter().next().unwrap()])
+                    } else {
+                        Node::concat(vec![first, Node::alt(rests)])
+                    }
+                })
+                .collect();
+
+            let mut class = BTreeSet::new();
+            alternatives.retain(|node| match node {
+                Node::Class(chars) => {
+                    class.extend(chars.iter().copied());
+                    false
+                }
+                _ => true,
+            });
+            if !class.is_empty() {
+                alternatives.push(Node::Class(class));
+            }
+
+            // common suffix of all alternatives, `ba|aa` becomes `(b|a)a`
+            if alternatives.len() > 1 {
+                let sequences: Vec<Vec<Node>> = alternatives
+                    .iter()
+                    .map(|node| node.clone().into_sequence())
+                    .collect();
+                let last = sequences[0].last().cloned();
+                if sequences
+                    .iter()
+                    .all(|seq| seq.len() > 1 && seq.last() == last.as_ref())
+                {
+                    let heads = sequences
+                        .into_iter()
+                        .map(|mut seq| {
+                            seq.pop();
+                            Node::concat(seq)
+                        })
+                        .collect();
+                    alternatives = vec![Node::concat(vec![Node::alt(heads), last.unwrap()])];
+                }
+            }
+
+            let node = match alternatives.len() {
+                0 => Node::Empty,
+                1 => alternatives.pop().unwrap(),
+                _ => Node::Alt(alternatives),
+            };
+            match (optional, node) {
+                (false, node) => node,
+                (true, Node::Empty) => Node::Empty,
+                (true, Node::Alt(mut alternatives)) => {
+                    alternatives.insert(0, Node::Empty);
+                    Node::Alt(alternatives)
+                }
+                (true, node) => Node::Alt(vec![Node::Empty, node]),
+            }
+        }
+
+        fn is_atom(&self) -> bool {
+            matches!(self, Node::Class(_))
+        }
+    }
+
+    fn escape(ch: char, in_class: bool, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let special = if in_class {
+            "\\]^-["
+        } else {
+            "\\.+*?()|[]{}^$#&-~"
+        };
+        if special.contains(ch) {
+            f.write_char('\\')?;
+        }
+        f.write_char(ch)
+    }
+
+    impl Display for Node {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Node::Empty => Ok(()),
+                Node::Class(chars) => {
+                    if chars.len() == 1 {
+                        escape(*chars.iter().next().unwrap(), false, f)
+                    } else {
+                        f.write_char('[')?;
+                        for ch in chars.iter() {
+                            escape(*ch, true, f)?;
+                        }
+                        f.write_char(']')
+                    }
+                }
+                Node::Concat(nodes) => {
+                    for node in nodes.iter() {
+                        match node {
+                            Node::Alt(_) => f.write_fmt(format_args!("({})", node))?,
+                            _ => Self::fmt(node, f)?,
+                        }
+                    }
+                    Ok(())
+                }
+                Node::Alt(alternatives) => {
+                    let (optional, alternatives) = match alternatives.split_first() {
+                        Some((Node::Empty, rest)) => (true, rest),
+                        _ => (false, alternatives.as_slice()),
+                    };
+                    if optional {
+                        f.write_char('(')?;
+                    }
+                    for (i, node) in alternatives.iter().enumerate() {
+                        if i > 0 {
+                            f.write_char('|')?;
+                        }
+                        Self::fmt(node, f)?;
+                    }
+                    if optional {
+                        f.write_str(")?")?;
+                    }
+                    Ok(())
+                }
+                Node::Star(node) => {
+                    if node.is_atom() {
+                        f.write_fmt(format_args!("{}*", node))
+                    } else {
+                        f.write_fmt(format_args!("({})*", node))
+                    }
+                }
+            }
+        }
+    }
+
+    struct Builder<'a> {
+        rules: &'a HashMap<u64, Pattern>,
+        // rules are usually referenced many times, only expand them once
+        cache: HashMap<u64, Node>,
+    }
+
+    impl<'a> Builder<'a> {
+        fn sequence(&mut self, seq: &[u64]) -> Result<Node> {
+            let nodes = seq
+                .iter()
+                .map(|rule| self.rule(*rule))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Node::concat(nodes))
+        }
+
+        fn alternatives(&mut self, alternatives: &[&[u64]]) -> Result<Node> {
+            let nodes = alternatives
+                .iter()
+                .map(|seq| self.sequence(seq))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Node::alt(nodes))
+        }
+
+        fn rule(&mut self, rule: u64) -> Result<Node> {
+            if let Some(node) = self.cache.get(&rule) {
+                return Ok(node.clone());
+            }
+            let pattern = self
+                .rules
+                .get(&rule)
+                .ok_or_else(|| Error::General(format!("Could not find rule {}", rule)))?;
+            let node = match analysis::linear(rule, pattern).map_err(Error::General)? {
+                None => match pattern {
+                    Pattern::Literal(literal) => {
+                        Node::concat(literal.chars().map(Node::char).collect())
+                    }
+                    _ => self.alternatives(&pattern.sequences())?,
+                },
+                Some(linear) => {
+                    let prefixes = self.alternatives(&linear.prefixes)?;
+                    let bases = self.alternatives(&linear.bases)?;
+                    let suffixes = self.alternatives(&linear.suffixes)?;
+                    let prefixes = if linear.prefixes.is_empty() {
+                        Node::Empty
+                    } else {
+                        Node::star(prefixes)
+                    };
+                    let suffixes = if linear.suffixes.is_empty() {
+                        Node::Empty
+                    } else {
+                        Node::star(suffixes)
+                    };
+                    Node::concat(vec![prefixes, bases, suffixes])
+                }
+            };
+            self.cache.insert(rule, node.clone());
+            Ok(node)
+        }
+    }
+
+    pub(super) fn build(rules: &HashMap<u64, Pattern>, start_rule: u64) -> Result<Node> {
+        // recursion that can not be expressed as repetition would never finish
+        analysis::analyze(rules, start_rule).check_regular()?;
+
+        Builder {
+            rules,
+            cache: HashMap::new(),
+        }
+        .rule(start_rule)
     }
 }
 
 fn build_regex_str(rules: &HashMap<u64, Pattern>, start_rule: u64) -> Result<String> {
-    let mut rx = String::new();
-    build_regex_recursive(start_rule, rules, &mut rx)?;
-
-    Ok(rx)
+    Ok(regex_ast::build(rules, start_rule)?.to_string())
 }
 
-fn build_regex(rules: &HashMap<u64, Pattern>, start_rule: u64) -> Result<regex_automata::Regex> {
+/// Builds a regex that only matches whole messages, alternatives of different lengths rule out
+/// comparing the length of a leftmost-first match instead.
+fn build_regex(rules: &HashMap<u64, Pattern>, start_rule: u64) -> Result<regex::Regex> {
     let rx = build_regex_str(rules, start_rule)?;
 
-    RegexBuilder::new()
-        .anchored(true)
-        .build(&rx)
+    regex::Regex::new(&format!("^(?:{})$", rx))
         .map_err(|e| Error::General(format!("Could not build regex: {}", e)))
 }
 
-fn main() -> Result<()> {
-    let lines: Vec<String> = read_file("data/19.txt")?;
+mod earley {
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::fmt::{Display, Formatter, Write};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Symbol {
+        Rule(u64),
+        Char(char),
+    }
+
+    impl Display for Symbol {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Symbol::Rule(r) => f.write_fmt(format_args!("{}", r)),
+                Symbol::Char(ch) => f.write_fmt(format_args!("{:?}", ch)),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(super) enum ParseTree {
+        Char(char),
+        Rule(u64, Vec<ParseTree>),
+    }
+
+    impl Display for ParseTree {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ParseTree::Char(ch) => f.write_char(*ch),
+                ParseTree::Rule(id, children) => {
+                    f.write_fmt(format_args!("{}(", id))?;
+                    for (i, child) in children.iter().enumerate() {
+                        if i > 0 {
+                            f.write_char(' ')?;
+                        }
+                        Self::fmt(child, f)?;
+                    }
+                    f.write_char(')')
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    struct Item {
+        rule: u64,
+        alternative: usize,
+        dot: usize,
+        origin: usize,
+    }
+
+    impl Item {
+        fn advance(self) -> Self {
+            Self {
+                dot: self.dot + 1,
+                ..self
+            }
+        }
+    }
+
+    /// A partially matched alternative that could not continue at the failure offset.
+    #[derive(Debug, Clone)]
+    pub(super) struct Expectation {
+        rule: u64,
+        symbols: Vec<Symbol>,
+        dot: usize,
+        origin: usize,
+    }
+
+    impl Display for Expectation {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.write_fmt(format_args!("{}:", self.rule))?;
+            for (i, symbol) in self.symbols.iter().enumerate() {
+                if i == self.dot {
+                    f.write_str(" \u{2022}")?;
+                }
+                f.write_fmt(format_args!(" {}", symbol))?;
+            }
+            f.write_fmt(format_args!(" from {}", self.origin))
+        }
+    }
+
+    /// Why a message is rejected: how far derivations of the start rule get and what they
+    /// expected there.
+    #[derive(Debug, Clone)]
+    pub(super) struct Explanation {
+        pub(super) start: u64,
+        pub(super) message: String,
+        /// length in characters of the longest prefix some derivation matches
+        pub(super) matched: usize,
+        /// whether the start rule matches that whole prefix
+        pub(super) complete: bool,
+        pub(super) expected: BTreeSet<char>,
+        /// alternatives waiting for a character or inside a rule at the failure offset
+        pub(super) tried: Vec<Expectation>,
+    }
+
+    impl Display for Explanation {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            f.write_fmt(format_args!("{}\n", self.message))?;
+            f.write_fmt(format_args!("{:>1$}\n", "^", self.matched + 1))?;
+            f.write_fmt(format_args!(
+                "rule {} matches a prefix of {} characters",
+                self.start, self.matched
+            ))?;
+            match self.message.chars().nth(self.matched) {
+                Some(ch) => f.write_fmt(format_args!(", found {:?}", ch))?,
+                None => f.write_str(", found the end of the message")?,
+            }
+            let mut expected: Vec<String> =
+                self.expected.iter().map(|ch| format!("{:?}", ch)).collect();
+            if self.complete {
+                expected.push("the end of the message".into());
+            }
+            f.write_fmt(format_args!(", expected {}", expected.join(" or ")))?;
+            for expectation in self.tried.iter() {
+                f.write_fmt(format_args!("\n  {}", expectation))?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Earley recognizer and parser, works for any context free rule set including
+    /// left and right recursive rules.
+    pub(super) struct Grammar {
+        productions: HashMap<u64, Vec<Vec<Symbol>>>,
+        nullable: HashSet<u64>,
+    }
+
+    // completed items as (rule, alternative, start, end)
+    type Completed = HashSet<(u64, usize, usize, usize)>;
+
+    impl Grammar {
+        pub(super) fn new(rules: &HashMap<u64, Pattern>) -> Result<Self> {
+            let productions: HashMap<u64, Vec<Vec<Symbol>>> = rules
+                .iter()
+                .map(|(id, pattern)| {
+                    let sequences = match pattern {
+                        Pattern::Literal(literal) => {
+                            vec![literal.chars().map(Symbol::Char).collect()]
+                        }
+                        Pattern::Sequence(seq) => {
+                            vec![seq.iter().map(|r| Symbol::Rule(*r)).collect()]
+                        }
+                        Pattern::Alternatives(alternatives) => alternatives
+                            .iter()
+                            .map(|seq| seq.iter().map(|r| Symbol::Rule(*r)).collect())
+                            .collect(),
+                    };
+                    (*id, sequences)
+                })
+                .collect();
+
+            for (id, sequences) in productions.iter() {
+                for symbol in sequences.iter().flatten() {
+                    if let Symbol::Rule(r) = symbol {
+                        if !productions.contains_key(r) {
+                            return Err(Error::General(format!(
+                                "Rule {} references undefined rule {}",
+                                id, r
+                            )));
+                        }
+                    }
+                }
+            }
+
+            let mut nullable = HashSet::new();
+            loop {
+                let before = nullable.len();
+                for (id, sequences) in productions.iter() {
+                    let is_nullable = sequences.iter().any(|seq| {
+                        seq.iter().all(|symbol| match symbol {
+                            Symbol::Rule(r) => nullable.contains(r),
+                            Symbol::Char(_) => false,
+                        })
+                    });
+                    if is_nullable {
+                        nullable.insert(*id);
+                    }
+                }
+                if nullable.len() == before {
+                    break;
+                }
+            }
+
+            Ok(Self {
+                productions,
+                nullable,
+            })
+        }
+
+        fn symbols(&self, item: &Item) -> &[Symbol] {
+            &self.productions[&item.rule][item.alternative]
+        }
+
+        fn check_start(&self, start: u64) -> Result<()> {
+            if self.productions.contains_key(&start) {
+                Ok(())
+            } else {
+                Err(Error::General(format!("Could not find rule {}", start)))
+            }
+        }
+
+        /// Runs the recognizer and returns the item sets and all completed items.
+        fn chart(&self, start: u64, input: &[char]) -> (Vec<Vec<Item>>, Completed) {
+            let len = input.len();
+            let mut sets: Vec<Vec<Item>> = vec![vec![]; len + 1];
+            let mut seen: Vec<HashSet<Item>> = vec![HashSet::new(); len + 1];
+            let mut completed = Completed::new();
+
+            let mut add = |sets: &mut Vec<Vec<Item>>, i: usize, item: Item| {
+                if seen[i].insert(item) {
+                    sets[i].push(item);
+                }
+            };
+
+            for alternative in 0..self.productions[&start].len() {
+                let item = Item {
+                    rule: start,
+                    alternative,
+                    dot: 0,
+                    origin: 0,
+                };
+                add(&mut sets, 0, item);
+            }
+
+            for i in 0..=len {
+                let mut idx = 0;
+                while idx < sets[i].len() {
+                    let item = sets[i][idx];
+                    idx += 1;
+
+                    let symbols = self.symbols(&item);
+                    match symbols.get(item.dot) {
+                        Some(Symbol::Rule(r)) => {
+                            for alternative in 0..self.productions[r].len() {
+                                let predicted = Item {
+                                    rule: *r,
+                                    alternative,
+                                    dot: 0,
+                                    origin: i,
+                                };
+                                add(&mut sets, i, predicted);
+                            }
+                            // Aycock and Horspool: skip over nullable rules right away
+                            if self.nullable.contains(r) {
+                                add(&mut sets, i, item.advance());
+                            }
+                        }
+                        Some(Symbol::Char(ch)) => {
+                            if i < len && input[i] == *ch {
+                                add(&mut sets, i + 1, item.advance());
+                            }
+                        }
+                        None => {
+                            completed.insert((item.rule, item.alternative, item.origin, i));
+                            let parents: Vec<Item> = sets[item.origin]
+                                .iter()
+                                .filter(|parent| {
+                                    self.symbols(parent).get(parent.dot)
+                                        == Some(&Symbol::Rule(item.rule))
+                                })
+                                .copied()
+                                .collect();
+                            for parent in parents {
+                                add(&mut sets, i, parent.advance());
+                            }
+                        }
+                    }
+                }
+            }
+
+            (sets, completed)
+        }
+
+        pub(super) fn matches(&self, start: u64, message: &str) -> Result<bool> {
+            self.check_start(start)?;
+            let input: Vec<char> = message.chars().collect();
+            let (_, completed) = self.chart(start, &input);
+            Ok(self.is_complete(&completed, start, 0, input.len()))
+        }
+
+        /// Explains why `message` does not match `start`, `None` if it does.
+        pub(super) fn explain(&self, start: u64, message: &str) -> Result<Option<Explanation>> {
+            self.check_start(start)?;
+            let input: Vec<char> = message.chars().collect();
+            let (sets, completed) = self.chart(start, &input);
+            if self.is_complete(&completed, start, 0, input.len()) {
+                return Ok(None);
+            }
+
+            let matched = sets.iter().rposition(|set| !set.is_empty()).unwrap_or(0);
+            let mut expected = BTreeSet::new();
+            let mut stack = vec![];
+            for item in sets[matched].iter() {
+                match self.symbols(item).get(item.dot) {
+                    Some(Symbol::Char(ch)) => {
+                        expected.insert(*ch);
+                        stack.push(*item);
+                    }
+                    Some(Symbol::Rule(_)) if item.dot > 0 => stack.push(*item),
+                    _ => {}
+                }
+            }
+
+            // add the alternatives waiting for the rules of the failed ones, up to the start rule
+            let mut waiting: HashSet<Item> = stack.iter().copied().collect();
+            while let Some(item) = stack.pop() {
+                for parent in sets[item.origin].iter() {
+                    if self.symbols(parent).get(parent.dot) == Some(&Symbol::Rule(item.rule))
+                        && waiting.insert(*parent)
+                    {
+                        stack.push(*parent);
+                    }
+                }
+            }
+
+            let mut tried: Vec<Expectation> = waiting
+                .into_iter()
+                // fresh predictions only repeat the rule that is waiting for them
+                .filter(|item| {
+                    let fresh = item.origin == matched && item.dot == 0;
+                    !fresh || !matches!(self.symbols(item).get(0), Some(Symbol::Rule(_)))
+                })
+                .map(|item| Expectation {
+                    rule: item.rule,
+                    symbols: self.symbols(&item).to_vec(),
+                    dot: item.dot,
+                    origin: item.origin,
+                })
+                .collect();
+            tried.sort_by_key(|e| (e.origin, e.rule, e.dot));
+
+            Ok(Some(Explanation {
+                start,
+                message: message.to_string(),
+                matched,
+                complete: self.is_complete(&completed, start, 0, matched),
+                expected,
+                tried,
+            }))
+        }
+
+        pub(super) fn parse(&self, start: u64, message: &str) -> Result<Option<ParseTree>> {
+            self.check_start(start)?;
+            let input: Vec<char> = message.chars().collect();
+            let (_, completed) = self.chart(start, &input);
+            Ok(self.build(
+                &completed,
+                &input,
+                start,
+                0,
+                input.len(),
+                &mut HashSet::new(),
+            ))
+        }
+
+        fn is_complete(&self, completed: &Completed, rule: u64, start: usize, end: usize) -> bool {
+            (0..self.productions[&rule].len())
+                .any(|alternative| completed.contains(&(rule, alternative, start, end)))
+        }
+
+        fn build(
+            &self,
+            completed: &Completed,
+            input: &[char],
+            rule: u64,
+            start: usize,
+            end: usize,
+            visiting: &mut HashSet<(u64, usize, usize)>,
+        ) -> Option<ParseTree> {
+            // a cyclic derivation like `1: 1` can not contribute to a finite tree
+            if !visiting.insert((rule, start, end)) {
+                return None;
+            }
+            let tree = self.productions[&rule]
+                .iter()
+                .enumerate()
+                .filter(|(alternative, _)| completed.contains(&(rule, *alternative, start, end)))
+                .find_map(|(_, symbols)| {
+                    self.build_sequence(completed, input, symbols, start, end, visiting)
+                })
+                .map(|children| ParseTree::Rule(rule, children));
+            visiting.remove(&(rule, start, end));
+            tree
+        }
+
+        fn build_sequence(
+            &self,
+            completed: &Completed,
+            input: &[char],
+            symbols: &[Symbol],
+            start: usize,
+            end: usize,
+            visiting: &mut HashSet<(u64, usize, usize)>,
+        ) -> Option<Vec<ParseTree>> {
+            let (first, rest) = match symbols.split_first() {
+                None if start == end => return Some(vec![]),
+                None => return None,
+                Some(split) => split,
+            };
+            match first {
+                Symbol::Char(ch) => {
+                    if start < end && input[start] == *ch {
+                        let mut children = vec![ParseTree::Char(*ch)];
+                        children.extend(self.build_sequence(
+                            completed,
+                            input,
+                            rest,
+                            start + 1,
+                            end,
+                            visiting,
+                        )?);
+                        Some(children)
+                    } else {
+                        None
+                    }
+                }
+                Symbol::Rule(r) => (start..=end).find_map(|mid| {
+                    if !self.is_complete(completed, *r, start, mid) {
+                        return None;
+                    }
+                    let child = self.build(completed, input, *r, start, mid, visiting)?;
+                    let siblings =
+                        self.build_sequence(completed, input, rest, mid, end, visiting)?;
+                    let mut children = vec![child];
+                    children.extend(siblings);
+                    Some(children)
+                }),
+            }
+        }
+    }
+}
+
+mod language {
+    use super::analysis;
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use std::collections::{BTreeSet, HashMap, HashSet};
+
+    // languages up to this size are materialized
+    const LIMIT: u128 = 1 << 16;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) struct Summary {
+        /// number of distinct matched strings, an upper bound if not `exact`
+        pub(super) count: u128,
+        pub(super) exact: bool,
+        /// lengths in characters
+        pub(super) min_len: usize,
+        pub(super) max_len: usize,
+    }
+
+    impl Summary {
+        fn fixed_len(&self) -> Option<usize> {
+            if self.min_len == self.max_len {
+                Some(self.min_len)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// The strings matched by the rules of a non-recursive rule set.
+    pub(super) struct Language<'a> {
+        rules: &'a HashMap<u64, Pattern>,
+        summaries: HashMap<u64, Summary>,
+        /// sorted words of every rule with at most `LIMIT` of them
+        words: HashMap<u64, Vec<String>>,
+    }
+
+    impl<'a> Language<'a> {
+        pub(super) fn new(rules: &'a HashMap<u64, Pattern>, start: u64) -> Result<Self> {
+            if !rules.contains_key(&start) {
+                return Err(Error::General(format!("Could not find rule {}", start)));
+            }
+            let analysis = analysis::analyze(rules, start);
+            analysis.check_regular()?;
+            if analysis.class != analysis::Class::Finite {
+                return Err(Error::General(format!(
+                    "Rule {} matches infinitely many strings",
+                    start
+                )));
+            }
+
+            let mut language = Self {
+                rules,
+                summaries: HashMap::new(),
+                words: HashMap::new(),
+            };
+            language.visit(start);
+            Ok(language)
+        }
+
+        fn visit(&mut self, rule: u64) -> Summary {
+            if let Some(summary) = self.summaries.get(&rule) {
+                return *summary;
+            }
+
+            let rules = self.rules;
+            let summary = match &rules[&rule] {
+                Pattern::Literal(literal) => {
+                    self.words.insert(rule, vec![literal.clone()]);
+                    let len = literal.chars().count();
+                    Summary {
+                        count: 1,
+                        exact: true,
+                        min_len: len,
+                        max_len: len,
+                    }
+                }
+                pattern => {
+                    let sequences = pattern.sequences();
+                    let alternatives: Vec<Summary> =
+                        sequences.iter().map(|seq| self.concat(seq)).collect();
+
+                    let min_len = alternatives.iter().map(|s| s.min_len).min().unwrap_or(0);
+                    let max_len = alternatives.iter().map(|s| s.max_len).max().unwrap_or(0);
+                    let count = alternatives
+                        .iter()
+                        .fold(0_u128, |count, s| count.saturating_add(s.count));
+
+                    if count <= LIMIT {
+                        // small enough to remove duplicates between the alternatives
+                        let mut words = BTreeSet::new();
+                        for seq in sequences {
+                            words.extend(self.concat_words(seq));
+                        }
+                        let count = words.len() as u128;
+                        self.words.insert(rule, words.into_iter().collect());
+                        Summary {
+                            count,
+                            exact: true,
+                            min_len,
+                            max_len,
+                        }
+                    } else {
+                        // alternatives with disjoint lengths can not match the same string
+                        let disjoint = alternatives.iter().enumerate().all(|(i, a)| {
+                            alternatives[i + 1..]
+                                .iter()
+                                .all(|b| a.max_len < b.min_len || b.max_len < a.min_len)
+                        });
+                        Summary {
+                            count,
+                            exact: disjoint && alternatives.iter().all(|s| s.exact),
+                            min_len,
+                            max_len,
+                        }
+                    }
+                }
+            };
+
+            self.summaries.insert(rule, summary);
+            summary
+        }
+
+        fn concat(&mut self, seq: &[u64]) -> Summary {
+            let parts: Vec<Summary> = seq.iter().map(|item| self.visit(*item)).collect();
+            // a string splits uniquely into the parts if all but the last have a fixed length
+            let unique = parts
+                .iter()
+                .take(parts.len().saturating_sub(1))
+                .all(|s| s.fixed_len().is_some());
+            Summary {
+                count: parts
+                    .iter()
+                    .fold(1_u128, |count, s| count.saturating_mul(s.count)),
+                exact: unique && parts.iter().all(|s| s.exact),
+                min_len: parts.iter().map(|s| s.min_len).sum(),
+                max_len: parts.iter().map(|s| s.max_len).sum(),
+            }
+        }
+
+        fn concat_words(&self, seq: &[u64]) -> Vec<String> {
+            seq.iter().fold(vec![String::new()], |prefixes, item| {
+                prefixes
+                    .iter()
+                    .flat_map(|prefix| {
+                        self.words[item]
+                            .iter()
+                            .map(move |word| format!("{}{}", prefix, word))
+                    })
+                    .collect()
+            })
+        }
+
+        pub(super) fn summary(&self, rule: u64) -> Option<Summary> {
+            self.summaries.get(&rule).copied()
+        }
+
+        /// The strings matched by `rule` in lexicographic order. Rules too large to be
+        /// materialized are supported if they are a single sequence of materialized rules
+        /// with fixed lengths, which are enumerated lazily.
+        pub(super) fn enumerate(&self, rule: u64) -> Result<Box<dyn Iterator<Item = String> + '_>> {
+            if let Some(words) = self.words.get(&rule) {
+                return Ok(Box::new(words.iter().cloned()));
+            }
+
+            let not_enumerable =
+                || Error::General(format!("Rule {} is too large to enumerate", rule));
+            let sequences = self
+                .rules
+                .get(&rule)
+                .ok_or_else(not_enumerable)?
+                .sequences();
+            let seq = match sequences.as_slice() {
+                [seq] => *seq,
+                _ => return Err(not_enumerable()),
+            };
+            let parts = seq
+                .iter()
+                .map(|item| {
+                    self.summaries[item]
+                        .fixed_len()
+                        .and_then(|_| self.words.get(item))
+                        .filter(|words| !words.is_empty())
+                        .map(|words| words.as_slice())
+                        .ok_or_else(not_enumerable)
+                })
+                .collect::<Result<Vec<&[String]>>>()?;
+
+            // odometer over the parts, with fixed lengths the product order is lexicographic
+            let mut indices = vec![0; parts.len()];
+            let mut done = false;
+            Ok(Box::new(std::iter::from_fn(move || {
+                if done {
+                    return None;
+                }
+                let word = indices
+                    .iter()
+                    .zip(parts.iter())
+                    .map(|(&i, words)| words[i].as_str())
+                    .collect();
+                done = true;
+                for (i, words) in indices.iter_mut().zip(parts.iter()).rev() {
+                    *i += 1;
+                    if *i < words.len() {
+                        done = false;
+                        break;
+                    }
+                    *i = 0;
+                }
+                Some(word)
+            })))
+        }
+    }
+
+    /// Matches the part 2 rule set `0: 8 11`, `8: 42 | 42 8`, `11: 42 31 | 42 11 31` by
+    /// splitting messages into blocks when 42 and 31 match strings of one fixed size.
+    ///
+    /// The language has to have the part 1 rules `0: 8 11`, `8: 42` and `11: 42 31`, with
+    /// `left` and `right` in place of 42 and 31.
+    pub(super) struct BlockMatcher {
+        size: usize,
+        left: HashSet<String>,
+        right: HashSet<String>,
+    }
+
+    impl BlockMatcher {
+        pub(super) fn new(language: &Language, left: u64, right: u64) -> Result<Self> {
+            let shapes = [
+                (0, "8 11".to_string()),
+                (8, left.to_string()),
+                (11, format!("{} {}", left, right)),
+            ];
+            for (rule, shape) in shapes.iter() {
+                let found = language.rules.get(rule).map(|pattern| pattern.to_string());
+                if found.as_ref() != Some(shape) {
+                    return Err(Error::General(format!(
+                        "Rule {} has to be {} but is {}",
+                        rule,
+                        shape,
+                        found.as_deref().unwrap_or("undefined")
+                    )));
+                }
+            }
+
+            let block = |rule: u64| -> Result<(usize, HashSet<String>)> {
+                let size = language
+                    .summary(rule)
+                    .and_then(|s| s.fixed_len())
+                    .ok_or_else(|| {
+                        Error::General(format!("Rule {} does not have a fixed length", rule))
+                    })?;
+                Ok((size, language.enumerate(rule)?.collect()))
+            };
+            let (size, left) = block(left)?;
+            let (right_size, right) = block(right)?;
+            if size != right_size || size == 0 {
+                return Err(Error::General(format!(
+                    "Block sizes {} and {} differ",
+                    size, right_size
+                )));
+            }
+            Ok(Self { size, left, right })
+        }
+
+        /// Splits `message` into blocks of `size` characters.
+        fn chunks(&self, message: &str) -> Option<Vec<String>> {
+            let chars: Vec<char> = message.chars().collect();
+            if chars.len() % self.size != 0 {
+                return None;
+            }
+            Some(
+                chars
+                    .chunks(self.size)
+                    .map(|chunk| chunk.iter().collect())
+                    .collect(),
+            )
+        }
+
+        /// Splits the chunks into `n` left blocks followed by `m` right blocks, preferring
+        /// the split with the most left blocks.
+        fn blocks(&self, chunks: &[String]) -> Option<(usize, usize)> {
+            let left = chunks
+                .iter()
+                .take_while(|chunk| self.left.contains(*chunk))
+                .count();
+            let right = chunks
+                .iter()
+                .rev()
+                .take_while(|chunk| self.right.contains(*chunk))
+                .count();
+            if left + right < chunks.len() {
+                return None;
+            }
+            let n = left.min(chunks.len());
+            Some((n, chunks.len() - n))
+        }
+
+        /// Part 1 matches exactly `42 42 31`.
+        pub(super) fn matches_part1(&self, message: &str) -> bool {
+            self.split(message, |n, m| n == 2 && m == 1)
+        }
+
+        /// Part 2 matches `42{n} 31{m}` with `n > m >= 1`.
+        pub(super) fn matches_part2(&self, message: &str) -> bool {
+            self.split(message, |n, m| m >= 1 && n > m)
+        }
+
+        fn split(&self, message: &str, valid: impl Fn(usize, usize) -> bool) -> bool {
+            let chunks = match self.chunks(message) {
+                Some(chunks) => chunks,
+                None => return false,
+            };
+            match self.blocks(&chunks) {
+                // blocks matched by both rules can be counted on either side
+                Some((n, m)) => (0..=n)
+                    .take_while(|&k| k == 0 || self.right.contains(&chunks[n - k]))
+                    .any(|k| valid(n - k, m + k)),
+                None => false,
+            }
+        }
+    }
+}
+
+mod ebnf {
+    use super::analysis;
+    use super::parse_literal;
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::fmt::{Display, Formatter, Write};
+
+    #[derive(Debug, Clone, Default)]
+    pub(super) struct Options {
+        /// replace rules referenced only once by their definition
+        pub(super) inline: bool,
+        /// names used instead of `r<id>`
+        pub(super) names: HashMap<u64, String>,
+    }
+
+    enum Term<'a> {
+        Rule(String),
+        Literal(&'a Pattern),
+        Group(Vec<Vec<Term<'a>>>),
+    }
+
+    impl Display for Term<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Term::Rule(name) => f.write_str(name),
+                Term::Literal(pattern) => pattern.fmt(f),
+                Term::Group(alternatives) => {
+                    f.write_str("( ")?;
+                    write_alternatives(alternatives, f)?;
+                    f.write_str(" )")
+                }
+            }
+        }
+    }
+
+    fn write_alternatives(alternatives: &[Vec<Term>], f: &mut dyn Write) -> std::fmt::Result {
+        for (i, seq) in alternatives.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            for (j, term) in seq.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(" , ")?;
+                }
+                f.write_fmt(format_args!("{}", term))?;
+            }
+        }
+        Ok(())
+    }
+
+    struct Exporter<'a> {
+        rules: &'a HashMap<u64, Pattern>,
+        inlined: HashSet<u64>,
+        options: &'a Options,
+    }
+
+    impl<'a> Exporter<'a> {
+        fn name(&self, rule: u64) -> String {
+            self.options
+                .names
+                .get(&rule)
+                .cloned()
+                .unwrap_or_else(|| format!("r{}", rule))
+        }
+
+        fn body(&self, rule: u64) -> Vec<Vec<Term<'a>>> {
+            let pattern = &self.rules[&rule];
+            if let Pattern::Literal(_) = pattern {
+                return vec![vec![Term::Literal(pattern)]];
+            }
+            pattern
+                .sequences()
+                .iter()
+                .map(|seq| {
+                    let mut terms = vec![];
+                    for item in seq.iter() {
+                        if !self.inlined.contains(item) {
+                            terms.push(Term::Rule(self.name(*item)));
+                            continue;
+                        }
+                        let mut body = self.body(*item);
+                        if body.len() == 1 {
+                            terms.extend(body.pop().unwrap());
+                        } else {
+                            terms.push(Term::Group(body));
+                        }
+                    }
+                    terms
+                })
+                .collect()
+        }
+    }
+
+    /// Writes the rules reachable from `start` as EBNF, `r0 = r8 , r11 ;`, in the order they
+    /// are first referenced. Terminals keep the escapes of the puzzle format.
+    pub(super) fn export(
+        rules: &HashMap<u64, Pattern>,
+        start: u64,
+        options: &Options,
+    ) -> Result<String> {
+        let analysis = analysis::analyze(rules, start);
+        if let Some((rule, missing)) = analysis.undefined.first() {
+            return Err(Error::General(format!(
+                "Rule {} references undefined rule {}",
+                rule, missing
+            )));
+        }
+
+        let mut order = vec![];
+        let mut uses: HashMap<u64, usize> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        let mut seen: HashSet<u64> = queue.iter().copied().collect();
+        while let Some(rule) = queue.pop_front() {
+            order.push(rule);
+            for item in rules[&rule].sequences().iter().flat_map(|seq| seq.iter()) {
+                *uses.entry(*item).or_default() += 1;
+                if seen.insert(*item) {
+                    queue.push_back(*item);
+                }
+            }
+        }
+
+        let inlined = if options.inline {
+            uses.iter()
+                .filter(|(rule, count)| {
+                    **count == 1
+                        && **rule != start
+                        && !analysis.cycles.iter().any(|cycle| cycle.contains(rule))
+                })
+                .map(|(rule, _)| *rule)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let exporter = Exporter {
+            rules,
+            inlined,
+            options,
+        };
+        let mut ebnf = String::new();
+        for rule in order.into_iter().filter(|r| !exporter.inlined.contains(r)) {
+            ebnf.push_str(&exporter.name(rule));
+            ebnf.push_str(" = ");
+            write_alternatives(&exporter.body(rule), &mut ebnf)
+                .map_err(|e| Error::General(e.to_string()))?;
+            ebnf.push_str(" ;\n");
+        }
+        Ok(ebnf)
+    }
+
+    enum Node {
+        Name(String, usize),
+        Literal(String),
+        Group(Vec<Vec<Node>>),
+    }
+
+    struct Parser<'s> {
+        source: &'s str,
+        pos: usize,
+    }
+
+    impl<'s> Parser<'s> {
+        fn error(&self, message: &str) -> Error {
+            Error::General(format!("{} at offset {} of EBNF", message, self.pos))
+        }
+
+        fn rest(&self) -> &'s str {
+            &self.source[self.pos..]
+        }
+
+        /// Skips whitespace and `(* comments *)`.
+        fn skip(&mut self) -> Result<()> {
+            loop {
+                let rest = self.rest();
+                let trimmed = rest.trim_start();
+                self.pos += rest.len() - trimmed.len();
+                if !trimmed.starts_with("(*") {
+                    return Ok(());
+                }
+                match trimmed.find("*)") {
+                    Some(end) => self.pos += end + 2,
+                    None => return Err(self.error("Unterminated comment")),
+                }
+            }
+        }
+
+        fn eat(&mut self, token: char) -> Result<bool> {
+            self.skip()?;
+            if self.rest().starts_with(token) {
+                self.pos += token.len_utf8();
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+
+        fn expect(&mut self, token: char) -> Result<()> {
+            if self.eat(token)? {
+                Ok(())
+            } else {
+                Err(self.error(&format!("Expected '{}'", token)))
+            }
+        }
+
+        fn identifier(&mut self) -> Result<Option<String>> {
+            self.skip()?;
+            let len = self
+                .rest()
+                .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+                .unwrap_or_else(|| self.rest().len());
+            if len == 0 {
+                return Ok(None);
+            }
+            let identifier = self.rest()[..len].to_string();
+            self.pos += len;
+            Ok(Some(identifier))
+        }
+
+        fn alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+            let mut alternatives = vec![self.sequence()?];
+            while self.eat('|')? {
+                alternatives.push(self.sequence()?);
+            }
+            Ok(alternatives)
+        }
+
+        fn sequence(&mut self) -> Result<Vec<Node>> {
+            let mut sequence = vec![];
+            while let Some(node) = self.term()? {
+                sequence.push(node);
+                // commas are optional between terms
+                self.eat(',')?;
+            }
+            Ok(sequence)
+        }
+
+        fn term(&mut self) -> Result<Option<Node>> {
+            self.skip()?;
+            let pos = self.pos;
+            if self.eat('"')? {
+                // find the closing quote, skipping escaped characters
+                let mut escaped = false;
+                let end = self
+                    .rest()
+                    .char_indices()
+                    .find(|&(_, ch)| {
+                        let end = ch == '"' && !escaped;
+                        escaped = ch == '\\' && !escaped;
+                        end
+                    })
+                    .map(|(i, _)| i + 1)
+                    .ok_or_else(|| self.error("Unterminated literal"))?;
+                let literal = parse_literal(&self.rest()[..end])?;
+                self.pos += end;
+                Ok(Some(Node::Literal(literal)))
+            } else if self.eat('(')? {
+                let alternatives = self.alternatives()?;
+                self.expect(')')?;
+                Ok(Some(Node::Group(alternatives)))
+            } else {
+                Ok(self.identifier()?.map(|name| Node::Name(name, pos)))
+            }
+        }
+    }
+
+    /// Rule ids of `r<id>` and plain numbers are kept.
+    fn numeric_id(name: &str) -> Option<u64> {
+        name.strip_prefix('r').unwrap_or(name).parse().ok()
+    }
+
+    struct Lowering {
+        ids: HashMap<String, u64>,
+        literals: HashMap<String, u64>,
+        rules: HashMap<u64, Pattern>,
+        next_id: u64,
+    }
+
+    impl Lowering {
+        fn fresh(&mut self) -> u64 {
+            self.next_id += 1;
+            self.next_id - 1
+        }
+
+        fn pattern(&mut self, alternatives: Vec<Vec<Node>>) -> Result<Pattern> {
+            let mut sequences = alternatives
+                .into_iter()
+                .map(|seq| {
+                    seq.into_iter()
+                        .map(|node| self.item(node))
+                        .collect::<Result<Vec<u64>>>()
+                })
+                .collect::<Result<Vec<Vec<u64>>>>()?;
+            if sequences.len() == 1 {
+                Ok(Pattern::Sequence(sequences.pop().unwrap()))
+            } else {
+                Ok(Pattern::Alternatives(sequences))
+            }
+        }
+
+        fn item(&mut self, node: Node) -> Result<u64> {
+            match node {
+                Node::Name(name, pos) => self.ids.get(&name).copied().ok_or_else(|| {
+                    Error::General(format!("Undefined rule {} at offset {} of EBNF", name, pos))
+                }),
+                Node::Literal(literal) => {
+                    if let Some(id) = self.literals.get(&literal) {
+                        return Ok(*id);
+                    }
+                    let id = self.fresh();
+                    self.literals.insert(literal.clone(), id);
+                    self.rules.insert(id, Pattern::Literal(literal));
+                    Ok(id)
+                }
+                Node::Group(alternatives) => {
+                    let id = self.fresh();
+                    let pattern = self.pattern(alternatives)?;
+                    self.rules.insert(id, pattern);
+                    Ok(id)
+                }
+            }
+        }
+    }
+
+    /// Reads rules `name = a , "b" | ( c | d ) ;` back into numbered rules. Names `r<id>` or
+    /// plain numbers keep their id, other names, terminals inside sequences and groups get
+    /// fresh ids. Returns the rules and the id of every name.
+    pub(super) fn import(source: &str) -> Result<(HashMap<u64, Pattern>, HashMap<String, u64>)> {
+        let mut parser = Parser { source, pos: 0 };
+        let mut definitions = vec![];
+        loop {
+            parser.skip()?;
+            if parser.rest().is_empty() {
+                break;
+            }
+            let name = parser
+                .identifier()?
+                .ok_or_else(|| parser.error("Expected rule name"))?;
+            parser.expect('=')?;
+            let alternatives = parser.alternatives()?;
+            parser.expect(';')?;
+            definitions.push((name, alternatives));
+        }
+
+        let mut ids = HashMap::new();
+        // `r5` and `5` are different names for the same rule
+        let mut names: HashMap<u64, &str> = HashMap::new();
+        for (name, _) in definitions.iter() {
+            if let Some(id) = numeric_id(name) {
+                match names.insert(id, name) {
+                    Some(other) if other == name => {
+                        return Err(Error::General(format!("Rule {} defined twice", name)))
+                    }
+                    Some(other) => {
+                        return Err(Error::General(format!(
+                            "Rules {} and {} both have id {}",
+                            other, name, id
+                        )))
+                    }
+                    None => {
+                        ids.insert(name.clone(), id);
+                    }
+                }
+            }
+        }
+        let mut next_id = ids.values().max().map_or(0, |id| id + 1);
+        for (name, _) in definitions.iter() {
+            if numeric_id(name).is_none() {
+                if ids.contains_key(name) {
+                    return Err(Error::General(format!("Rule {} defined twice", name)));
+                }
+                ids.insert(name.clone(), next_id);
+                next_id += 1;
+            }
+        }
+        let mut lowering = Lowering {
+            ids,
+            literals: HashMap::new(),
+            rules: HashMap::new(),
+            next_id,
+        };
+        for (name, alternatives) in definitions {
+            let id = lowering.ids[&name];
+            let literal = match alternatives.as_slice() {
+                [seq] => match seq.as_slice() {
+                    [Node::Literal(literal)] => Some(literal.clone()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let pattern = match literal {
+                Some(literal) => Pattern::Literal(literal),
+                None => lowering.pattern(alternatives)?,
+            };
+            lowering.rules.insert(id, pattern);
+        }
+
+        Ok((lowering.rules, lowering.ids))
+    }
+}
+
+mod generator {
+    use super::Error;
+    use super::Pattern;
+    use super::Result;
+    use super::XorShift;
+    use std::collections::{BTreeSet, HashMap};
+
+    /// Random message generator for a rule set. Recursive rules are expanded until the
+    /// derivation reaches `max_depth`, after which only the shallowest alternatives are used.
+    pub(super) struct Generator<'a> {
+        rules: &'a HashMap<u64, Pattern>,
+        // height of the smallest derivation tree of every productive rule
+        heights: HashMap<u64, usize>,
+        alphabet: Vec<char>,
+        max_depth: usize,
+        rng: XorShift,
+    }
+
+    impl<'a> Generator<'a> {
+        pub(super) fn new(
+            rules: &'a HashMap<u64, Pattern>,
+            max_depth: usize,
+            seed: u64,
+        ) -> Result<Self> {
+            if let Some((id, undefined)) = rules.iter().find_map(|(id, pattern)| {
+                pattern
+                    .sequences()
+                    .iter()
+                    .flat_map(|seq| seq.iter())
+                    .find(|item| !rules.contains_key(item))
+                    .map(|item| (id, item))
+            }) {
+                return Err(Error::General(format!(
+                    "Rule {} references undefined rule {}",
+                    id, undefined
+                )));
+            }
+
+            let mut heights = HashMap::new();
+            loop {
+                let mut changed = false;
+                for (id, pattern) in rules {
+                    let height = match pattern {
+                        Pattern::Literal(_) => Some(1),
+                        _ => pattern
+                            .sequences()
+                            .iter()
+                            .filter_map(|seq| Self::sequence_height(&heights, seq))
+                            .min(),
+                    };
+                    if let Some(height) = height {
+                        if heights.get(id).map_or(true, |&h| height < h) {
+                            heights.insert(*id, height);
+                            changed = true;
+                        }
+                    }
+                }
+                if !changed {
+                    break;
+                }
+            }
+
+            let alphabet: BTreeSet<char> = rules
+                .values()
+                .filter_map(|pattern| match pattern {
+                    Pattern::Literal(literal) => Some(literal.chars()),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+
+            if alphabet.is_empty() {
+                return Err(Error::General("Rules contain no literals".into()));
+            }
+
+            Ok(Self {
+                rules,
+                heights,
+                alphabet: alphabet.into_iter().collect(),
+                max_depth,
+                rng: XorShift::new(seed),
+            })
+        }
+
+        fn sequence_height(heights: &HashMap<u64, usize>, seq: &[u64]) -> Option<usize> {
+            seq.iter()
+                .map(|item| heights.get(item))
+                .try_fold(0, |height, h| h.map(|&h| height.max(h)))
+                .map(|height| height + 1)
+        }
+
+        /// A random message matching `start`.
+        pub(super) fn generate(&mut self, start: u64) -> Result<String> {
+            if !self.heights.contains_key(&start) {
+                return Err(Error::General(format!(
+                    "Rule {} does not match any finite message",
+                    start
+                )));
+            }
+            let mut message = String::new();
+            self.expand(start, 0, &mut message);
+            Ok(message)
+        }
+
+        fn expand(&mut self, rule: u64, depth: usize, message: &mut String) {
+            let rules = self.rules;
+            let sequences = match &rules[&rule] {
+                Pattern::Literal(literal) => {
+                    message.push_str(literal);
+                    return;
+                }
+                pattern => pattern.sequences(),
+            };
+
+            let heights: Vec<Option<usize>> = sequences
+                .iter()
+                .map(|seq| Self::sequence_height(&self.heights, seq))
+                .collect();
+            let remaining = self.max_depth.saturating_sub(depth);
+            let mut candidates: Vec<usize> = (0..sequences.len())
+                .filter(|&i| heights[i].map_or(false, |h| h <= remaining))
+                .collect();
+            if candidates.is_empty() {
+                let shallowest = heights.iter().filter_map(|&h| h).min();
+                candidates = (0..sequences.len())
+                    .filter(|&i| heights[i] == shallowest)
+                    .collect();
+            }
+
+            let choice = candidates[self.rng.below(candidates.len())];
+            for &item in sequences[choice] {
+                self.expand(item, depth + 1, message);
+            }
+        }
+
+        /// A near miss of `message`: a single character substituted, inserted or deleted, two
+        /// neighbours swapped or the message truncated. The result differs from `message`
+        /// but may still be valid, so callers have to classify it with a recognizer.
+        pub(super) fn mutate(&mut self, message: &str) -> String {
+            let chars: Vec<char> = message.chars().collect();
+            loop {
+                let mut mutated = chars.clone();
+                let at = self.rng.below(chars.len() + 1);
+                let random = self.alphabet[self.rng.below(self.alphabet.len())];
+                match self.rng.below(5) {
+                    0 if at < chars.len() => mutated[at] = random,
+                    1 => mutated.insert(at, random),
+                    2 if at < chars.len() => {
+                        mutated.remove(at);
+                    }
+                    3 if at + 1 < chars.len() => mutated.swap(at, at + 1),
+                    4 if at < chars.len() => mutated.truncate(at),
+                    _ => continue,
+                }
+                if mutated != chars {
+                    return mutated.into_iter().collect();
+                }
+            }
+        }
+    }
+}
+
+fn match_nested(bytes: &[u8], left: &Regex, right: &Regex, level: u64) -> bool {
+    if level > 0 && bytes.is_empty() {
+        return true;
+    }
+    if let Some(len1) = right.reverse().rfind(bytes) {
+        if let Some(len2) = left.forward().find(&bytes[0..len1]) {
+            match_nested(&bytes[len2..len1], left, right, level + 1)
+        } else {
+            false
+        }
+    } else {
+        false
+    }
+}
+
+fn match_new_rules(bytes: &[u8], left: &Regex, right: &Regex) -> bool {
+    let mut bytes = bytes;
+    while let Some(len) = left.forward().find(bytes) {
+        bytes = &bytes[len..];
+        if match_nested(bytes, left, right, 0) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Greedily consumes blocks matching `left`, then blocks matching `right`, returns the
+/// number of both and the consumed length.
+fn count_blocks(bytes: &[u8], left: &Regex, right: &Regex) -> (usize, usize, usize) {
+    let mut consumed = 0;
+    let mut count = |rx: &Regex| {
+        let mut blocks = 0;
+        while let Some(len) = rx.forward().find(&bytes[consumed..]).filter(|len| *len > 0) {
+            consumed += len;
+            blocks += 1;
+        }
+        blocks
+    };
+    let lefts = count(left);
+    let rights = count(right);
+    (lefts, rights, consumed)
+}
+
+/// Writes a stress corpus in the puzzle format with a hundred thousand messages, half of
+/// them near misses, and times the nested and the Earley matcher on it.
+fn corpus(
+    generator: &mut generator::Generator,
+    rules: &HashMap<u64, Pattern>,
+    left: &Regex,
+    right: &Regex,
+) -> Result<()> {
+    let path = std::env::temp_dir().join("a19_corpus.txt");
+    let mut ids: Vec<&u64> = rules.keys().collect();
+    ids.sort();
+    let mut generated = String::new();
+    for id in ids {
+        generated.push_str(&format!("{}: {}\n", id, rules[id]));
+    }
+    generated.push('\n');
+    for _ in 0..50_000 {
+        let valid = generator.generate(0)?;
+        // an empty line would end the messages
+        let near_miss = loop {
+            let near_miss = generator.mutate(&valid);
+            if !near_miss.is_empty() {
+                break near_miss;
+            }
+        };
+        generated.push_str(&near_miss);
+        generated.push('\n');
+        generated.push_str(&valid);
+        generated.push('\n');
+    }
+    std::fs::write(&path, generated)?;
+
+    let (rules, messages) = read_puzzle(path.to_str().unwrap())?;
+
+    let start = Instant::now();
+    let matching = messages
+        .iter()
+        .filter(|msg| match_new_rules(msg.as_bytes(), left, right))
+        .count();
+    println!("nested: {:?}", start.elapsed());
+    println!("{} of {}", matching, messages.len());
+
+    let grammar = earley::Grammar::new(&rules)?;
+    let start = Instant::now();
+    let matching = messages.iter().try_fold(0, |count, msg| -> Result<usize> {
+        Ok(count + grammar.matches(0, msg)? as usize)
+    })?;
+    println!("earley: {:?}", start.elapsed());
+    println!("{} of {}", matching, messages.len());
+
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+/// Reads the rules and the messages of a puzzle input.
+fn read_puzzle(path: &str) -> Result<(HashMap<u64, Pattern>, Vec<String>)> {
+    let lines: Vec<String> = read_file(path)?;
     let mut split = lines.split(|l| l.is_empty());
     let rules = split
         .next()
@@ -166,7 +2113,7 @@ fn main() -> Result<()> {
         .next()
         .ok_or_else(|| Error::General("Missing messages".into()))?;
 
-    let mut rules: HashMap<u64, Pattern> = rules.into_iter().try_fold(
+    let rules: HashMap<u64, Pattern> = rules.iter().try_fold(
         HashMap::default(),
         |mut map, line| -> Result<HashMap<u64, Pattern>> {
             let rule = Rule::from_str(line)?;
@@ -175,20 +2122,20 @@ fn main() -> Result<()> {
         },
     )?;
 
+    Ok((rules, messages.to_vec()))
+}
+
+fn main() -> Result<()> {
+    let (mut rules, messages) = read_puzzle("data/19.txt")?;
+
     let regex = build_regex(&rules, 0)?;
 
-    let part1 = messages
-        .iter()
-        .filter(|msg| match regex.forward().find(msg.as_bytes()) {
-            None => false,
-            Some(len) => len == msg.len(),
-        })
-        .count();
+    let part1 = messages.iter().filter(|msg| regex.is_match(msg)).count();
 
     println!("{}", part1);
 
-    let mut fortytwo = build_regex_str(&rules, 42)?;
-    let mut thirtyone = build_regex_str(&rules, 31)?;
+    let fortytwo = build_regex_str(&rules, 42)?;
+    let thirtyone = build_regex_str(&rules, 31)?;
 
     let rx_fortytwo = RegexBuilder::new()
         .anchored(true)
@@ -201,41 +2148,461 @@ fn main() -> Result<()> {
         .build(&thirtyone)
         .map_err(|e| Error::General(format!("Could not build regex: {}", e)))?;
 
-    fn match_nested(bytes: &[u8], left: &Regex, right: &Regex, level: u64) -> bool {
-        if level > 0 && bytes.len() == 0 {
-            return true;
+    let part2 = messages
+        .iter()
+        .filter(|msg| {
+            let bytes = msg.as_bytes();
+            match_new_rules(bytes, &rx_fortytwo, &rx_thirtyone)
+        })
+        .count();
+
+    println!("{}", part2);
+
+    let language = language::Language::new(&rules, 0)?;
+    for rule in &[42, 31, 0] {
+        if let Some(summary) = language.summary(*rule) {
+            println!(
+                "{}: {}{} strings of length {}..={}",
+                rule,
+                if summary.exact { "" } else { "at most " },
+                summary.count,
+                summary.min_len,
+                summary.max_len
+            );
         }
-        if let Some(len1) = right.reverse().rfind(bytes) {
-            if let Some(len2) = left.forward().find(&bytes[0..len1]) {
-                match_nested(&bytes[len2..len1], left, right, level + 1)
-            } else {
-                false
-            }
-        } else {
-            false
+    }
+    println!("{:?}", language.enumerate(0)?.take(3).collect::<Vec<_>>());
+
+    // `blocks` counts both parts again by splitting messages into blocks of 42 and 31
+    if std::env::args().nth(1).as_deref() == Some("blocks") {
+        let blocks = language::BlockMatcher::new(&language, 42, 31)?;
+        for matches in &[
+            language::BlockMatcher::matches_part1,
+            language::BlockMatcher::matches_part2,
+        ] {
+            let count = messages.iter().filter(|msg| matches(&blocks, msg)).count();
+            println!("{}", count);
         }
     }
 
-    fn match_new_rules(bytes: &[u8], left: &Regex, right: &Regex) -> bool {
-        let mut bytes = bytes;
-        while let Some(len) = left.forward().find(bytes) {
-            bytes = &bytes[len..];
-            if match_nested(bytes, left, right, 0) {
-                return true;
+    rules.insert(8, Pattern::from_str("42 | 42 8")?);
+    rules.insert(11, Pattern::from_str("42 31 | 42 11 31")?);
+
+    println!("{}", analysis::analyze(&rules, 0));
+    if let Err(e) = build_regex(&rules, 0) {
+        println!("{}", e);
+    }
+    // rule 8 alone is regular and compiles to a repetition
+    let regex = build_regex(&rules, 8)?;
+    let repeated_fortytwo = messages.iter().filter(|msg| regex.is_match(msg)).count();
+    println!("{}", repeated_fortytwo);
+
+    let grammar = earley::Grammar::new(&rules)?;
+
+    for msg in messages.iter() {
+        if let Some(tree) = grammar.parse(0, msg)? {
+            println!("{}", tree);
+            break;
+        }
+    }
+
+    // `explain` without messages explains every rejected message, otherwise only the first
+    let mut args = std::env::args().skip(1);
+    let explain_all = args.next().as_deref() == Some("explain");
+    let given: Vec<String> = if explain_all { args.collect() } else { vec![] };
+    let explained = if given.is_empty() { &messages } else { &given };
+    for msg in explained {
+        if let Some(explanation) = grammar.explain(0, msg)? {
+            println!("{}", explanation);
+            let (lefts, rights, consumed) =
+                count_blocks(msg.as_bytes(), &rx_fortytwo, &rx_thirtyone);
+            println!(
+                "42 x {}, 31 x {}, {} of {} bytes consumed",
+                lefts,
+                rights,
+                consumed,
+                msg.len()
+            );
+            if !explain_all {
+                break;
             }
         }
-        return false;
     }
 
-    let part2 = messages
-        .iter()
-        .filter(|msg| {
-            let mut bytes = msg.as_bytes();
-            match_new_rules(bytes, &rx_fortytwo, &rx_thirtyone)
-        })
-        .count();
+    // `ebnf` exports the part 2 rules, `ebnf <file>` counts the messages matched by the rule
+    // `message` of a grammar read from the file instead
+    if std::env::args().nth(1).as_deref() == Some("ebnf") {
+        match std::env::args().nth(2) {
+            Some(path) => {
+                let (imported, ids) = ebnf::import(&std::fs::read_to_string(path)?)?;
+                let start = *ids
+                    .get("message")
+                    .ok_or_else(|| Error::General("Could not find rule message".into()))?;
+                let grammar = earley::Grammar::new(&imported)?;
+                let count = messages.iter().try_fold(0, |count, msg| -> Result<usize> {
+                    Ok(count + grammar.matches(start, msg)? as usize)
+                })?;
+                println!("{}", count);
+            }
+            None => {
+                let mut options = ebnf::Options::default();
+                options.names.insert(0, "message".into());
+                options.names.insert(42, "left".into());
+                options.names.insert(31, "right".into());
+                print!("{}", ebnf::export(&rules, 0, &options)?);
+            }
+        }
+    }
 
-    println!("{}", part2);
+    if std::env::args().nth(1).as_deref() == Some("corpus") {
+        let mut generator = generator::Generator::new(&rules, 30, 0x5eed)?;
+        corpus(&mut generator, &rules, &rx_fortytwo, &rx_thirtyone)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn rules(lines: &[&str]) -> HashMap<u64, Pattern> {
+        lines
+            .iter()
+            .map(|line| Rule::from_str(line).unwrap())
+            .map(|rule| (rule.id, rule.pattern))
+            .collect()
+    }
+
+    /// Feeds `count` generated messages and a near miss of each to the matchers. Generated
+    /// messages have to be accepted by `reference`, returns the messages where any of the
+    /// `matchers` disagrees with it.
+    fn cross_check(
+        generator: &mut generator::Generator,
+        start: u64,
+        count: usize,
+        reference: &dyn Fn(&str) -> bool,
+        matchers: &[&dyn Fn(&str) -> bool],
+    ) -> Result<Vec<String>> {
+        let mut disagreements = vec![];
+        for _ in 0..count {
+            let valid = generator.generate(start)?;
+            if !reference(&valid) {
+                return Err(Error::General(format!(
+                    "Generated message {} is rejected",
+                    valid
+                )));
+            }
+            let near_miss = generator.mutate(&valid);
+            for msg in vec![valid, near_miss] {
+                let expected = reference(&msg);
+                if matchers.iter().any(|matcher| matcher(&msg) != expected) {
+                    disagreements.push(msg);
+                }
+            }
+        }
+        Ok(disagreements)
+    }
+
+    /// Random rule sets without recursion, where alternatives differ in length and the
+    /// literals have one to three characters.
+    fn random_rules(rng: &mut XorShift, count: u64) -> HashMap<u64, Pattern> {
+        let mut rules = HashMap::new();
+        for id in 0..count {
+            let remaining = count - id - 1;
+            let pattern = if remaining == 0 || rng.below(3) == 0 {
+                let len = 1 + rng.below(3);
+                Pattern::Literal((0..len).map(|_| ['a', 'b'][rng.below(2)]).collect())
+            } else {
+                let mut sequence = || -> Vec<u64> {
+                    (0..1 + rng.below(3))
+                        .map(|_| id + 1 + rng.below(remaining as usize) as u64)
+                        .collect()
+                };
+                Pattern::Alternatives(vec![sequence(), sequence()])
+            };
+            rules.insert(id, pattern);
+        }
+        rules
+    }
+
+    #[test]
+    fn regex_matches_like_earley() {
+        let (rules, _) = read_puzzle("data/19.txt").unwrap();
+        let mut rng = XorShift::new(0x5eed);
+        let mut rule_sets = vec![rules];
+        rule_sets.extend((0..50).map(|_| random_rules(&mut rng, 8)));
+        for rules in &rule_sets {
+            let grammar = earley::Grammar::new(rules).unwrap();
+            let regex = build_regex(rules, 0).unwrap();
+            let mut generator = generator::Generator::new(rules, 20, rng.next_u64()).unwrap();
+            let disagreements = cross_check(
+                &mut generator,
+                0,
+                200,
+                &|msg| grammar.matches(0, msg).unwrap(),
+                &[&|msg| regex.is_match(msg)],
+            )
+            .unwrap();
+            assert!(disagreements.is_empty(), "{:?}", disagreements);
+        }
+    }
+
+    #[test]
+    fn nested_matches_like_earley() {
+        let (mut rules, _) = read_puzzle("data/19.txt").unwrap();
+        rules.insert(8, Pattern::from_str("42 | 42 8").unwrap());
+        rules.insert(11, Pattern::from_str("42 31 | 42 11 31").unwrap());
+        let grammar = earley::Grammar::new(&rules).unwrap();
+        let fortytwo = RegexBuilder::new()
+            .anchored(true)
+            .build(&build_regex_str(&rules, 42).unwrap())
+            .unwrap();
+        let thirtyone = RegexBuilder::new()
+            .anchored(true)
+            .build(&build_regex_str(&rules, 31).unwrap())
+            .unwrap();
+        let mut generator = generator::Generator::new(&rules, 30, 0x5eed).unwrap();
+        let disagreements = cross_check(
+            &mut generator,
+            0,
+            1000,
+            &|msg| grammar.matches(0, msg).unwrap(),
+            &[&|msg| match_new_rules(msg.as_bytes(), &fortytwo, &thirtyone)],
+        )
+        .unwrap();
+        assert!(disagreements.is_empty(), "{:?}", disagreements);
+    }
+
+    #[test]
+    fn earley_matches_part2() {
+        let (mut rules, messages) = read_puzzle("data/19.txt").unwrap();
+        rules.insert(8, Pattern::from_str("42 | 42 8").unwrap());
+        rules.insert(11, Pattern::from_str("42 31 | 42 11 31").unwrap());
+        let grammar = earley::Grammar::new(&rules).unwrap();
+        let matching = messages
+            .iter()
+            .filter(|msg| grammar.matches(0, msg).unwrap())
+            .count();
+        assert_eq!(matching, 414);
+        assert!(grammar.matches(1000, "a").is_err());
+        assert!(grammar.explain(1000, "a").is_err());
+        assert!(grammar.parse(1000, "a").is_err());
+    }
+
+    #[test]
+    fn explain_rejected_message() {
+        let rules = rules(&["0: 1 2", "1: \"a\"", "2: 3 | 4", "3: \"b\"", "4: \"c\""]);
+        let grammar = earley::Grammar::new(&rules).unwrap();
+        assert!(grammar.explain(0, "ab").unwrap().is_none());
+
+        let explanation = grammar.explain(0, "ad").unwrap().unwrap();
+        assert_eq!(explanation.matched, 1);
+        assert_eq!(explanation.expected, "bc".chars().collect());
+        assert!(!explanation.complete);
+        let mut tried: Vec<String> = explanation.tried.iter().map(|e| e.to_string()).collect();
+        tried.sort();
+        assert_eq!(
+            tried,
+            vec![
+                "0: 1 \u{2022} 2 from 0",
+                "3: \u{2022} 'b' from 1",
+                "4: \u{2022} 'c' from 1"
+            ]
+        );
+
+        let explanation = grammar.explain(0, "abb").unwrap().unwrap();
+        assert_eq!(explanation.matched, 2);
+        assert!(explanation.expected.is_empty());
+        assert!(explanation.complete);
+        assert!(explanation.tried.is_empty());
+    }
+
+    #[test]
+    fn count_blocks_of_part2_message() {
+        let (rules, messages) = read_puzzle("data/19.txt").unwrap();
+        let fortytwo = RegexBuilder::new()
+            .anchored(true)
+            .build(&build_regex_str(&rules, 42).unwrap())
+            .unwrap();
+        let thirtyone = RegexBuilder::new()
+            .anchored(true)
+            .build(&build_regex_str(&rules, 31).unwrap())
+            .unwrap();
+        let part1 = build_regex(&rules, 0).unwrap();
+        let msg = messages
+            .iter()
+            .find(|msg| {
+                !part1.is_match(msg) && match_new_rules(msg.as_bytes(), &fortytwo, &thirtyone)
+            })
+            .unwrap();
+        // six blocks of 42 followed by one of 31, which part 1 with its `42 42 31` rejects
+        assert_eq!(
+            count_blocks(msg.as_bytes(), &fortytwo, &thirtyone),
+            (6, 1, msg.len())
+        );
+    }
+
+    #[test]
+    fn block_matching() {
+        let (rules, messages) = read_puzzle("data/19.txt").unwrap();
+        let language = language::Language::new(&rules, 0).unwrap();
+        let blocks = language::BlockMatcher::new(&language, 42, 31).unwrap();
+        let part1 = messages
+            .iter()
+            .filter(|msg| blocks.matches_part1(msg))
+            .count();
+        let part2 = messages
+            .iter()
+            .filter(|msg| blocks.matches_part2(msg))
+            .count();
+        assert_eq!((part1, part2), (299, 414));
+    }
+
+    #[test]
+    fn language_in_characters() {
+        let mixed = rules(&["0: 1 2 | 2", "1: \"\u{e9}\"", "2: \"ab\""]);
+        assert!(language::Language::new(&mixed, 3).is_err());
+        let language = language::Language::new(&mixed, 0).unwrap();
+        let summary = language.summary(0).unwrap();
+        assert_eq!((summary.min_len, summary.max_len), (2, 3));
+
+        let repeated = rules(&["0: 8 11", "8: 1", "11: 1 1", "1: \"\u{e9}\""]);
+        let language = language::Language::new(&repeated, 0).unwrap();
+        let blocks = language::BlockMatcher::new(&language, 1, 1).unwrap();
+        assert!(blocks.matches_part1("\u{e9}\u{e9}\u{e9}"));
+    }
+
+    #[test]
+    fn block_matching_needs_part1_rules() {
+        let mut rules = rules(&["0: 8 11", "8: 1", "11: 1 2", "1: \"a\"", "2: \"b\""]);
+        let language = language::Language::new(&rules, 0).unwrap();
+        assert!(language::BlockMatcher::new(&language, 1, 2).is_ok());
+        let error = language::BlockMatcher::new(&language, 2, 1).err().unwrap();
+        assert_eq!(error.to_string(), "General: Rule 8 has to be 2 but is 1");
+
+        rules.insert(11, Pattern::from_str("2 1").unwrap());
+        let language = language::Language::new(&rules, 0).unwrap();
+        let error = language::BlockMatcher::new(&language, 1, 2).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "General: Rule 11 has to be 1 2 but is 2 1"
+        );
+    }
+
+    #[test]
+    fn ebnf_round_trip() {
+        let (mut rules, messages) = read_puzzle("data/19.txt").unwrap();
+        let exported = ebnf::export(&rules, 0, &ebnf::Options::default()).unwrap();
+        let (imported, ids) = ebnf::import(&exported).unwrap();
+        let regex = build_regex(&imported, ids["r0"]).unwrap();
+        assert_eq!(
+            messages.iter().filter(|msg| regex.is_match(msg)).count(),
+            299
+        );
+
+        rules.insert(8, Pattern::from_str("42 | 42 8").unwrap());
+        rules.insert(11, Pattern::from_str("42 31 | 42 11 31").unwrap());
+        let mut options = ebnf::Options {
+            inline: true,
+            ..ebnf::Options::default()
+        };
+        options.names.insert(0, "message".into());
+        options.names.insert(42, "left".into());
+        options.names.insert(31, "right".into());
+        let (imported, ids) = ebnf::import(&ebnf::export(&rules, 0, &options).unwrap()).unwrap();
+        let grammar = earley::Grammar::new(&imported).unwrap();
+        let matching = messages
+            .iter()
+            .filter(|msg| grammar.matches(ids["message"], msg).unwrap())
+            .count();
+        assert_eq!(matching, 414);
+    }
+
+    #[test]
+    fn ebnf_ids_defined_once() {
+        assert!(ebnf::import(r#"r5 = "a" ; 5 = "b" ;"#).is_err());
+        assert!(ebnf::import(r#"r5 = "a" ; r5 = "b" ;"#).is_err());
+        assert!(ebnf::import(r#"r5 = "a" ; five = "b" ;"#).is_ok());
+    }
+
+    #[test]
+    fn mutual_recursion_is_not_context_free() {
+        let mut rules = rules(&["0: 8", "8: 42 9", "9: 42 | 8", "42: \"a\""]);
+        let analysis = analysis::analyze(&rules, 0);
+        assert_eq!(analysis.class, analysis::Class::MutuallyRecursive);
+        assert_eq!(analysis.cycles, vec![vec![8, 9]]);
+        assert!(build_regex(&rules, 0).is_err());
+
+        rules.insert(9, Pattern::from_str("42 | 42 9 42").unwrap());
+        assert_eq!(
+            analysis::analyze(&rules, 0).class,
+            analysis::Class::ContextFree
+        );
+        rules.insert(9, Pattern::from_str("42 | 42 9").unwrap());
+        assert_eq!(analysis::analyze(&rules, 0).class, analysis::Class::Regular);
+    }
+
+    #[test]
+    fn escaped_literals() {
+        let literals = rules(&["0: 1 2 | 2", r#"1: "(a.b)""#, r#"2: "say \"hi\"""#]);
+        assert_eq!(
+            build_regex_str(&literals, 0).unwrap(),
+            r#"(\(a\.b\)s|s)ay "hi""#
+        );
+        let regex = build_regex(&literals, 0).unwrap();
+        assert!(regex.is_match(r#"(a.b)say "hi""#));
+        assert!(regex.is_match(r#"say "hi""#));
+        assert!(!regex.is_match(r#"(aXb)say "hi""#));
+    }
+
+    #[test]
+    fn invalid_literals() {
+        let errors: Vec<String> = [r#""""#, r#""abc"#, r#""a\qb""#, r#""a" b"#]
+            .iter()
+            .map(|invalid| Pattern::from_str(invalid).unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "General: Empty literal",
+                r#"General: Unterminated literal "abc"#,
+                r#"General: Invalid escape \q in literal "a\qb""#,
+                r#"General: Unexpected ' b' after literal "a" b"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn only_reachable_undefined_rules_fail() {
+        let rules = rules(&["0: 42 5", "5: 7", "42: \"a\""]);
+        let analysis = analysis::analyze(&rules, 42);
+        assert_eq!(analysis.undefined, vec![(5, 7)]);
+        assert_eq!(analysis.unreachable, vec![0, 5]);
+        assert!(build_regex(&rules, 42).unwrap().is_match("a"));
+        assert_eq!(
+            build_regex_str(&rules, 0).unwrap_err().to_string(),
+            "General: Rule 5 references undefined rule 7"
+        );
+    }
+
+    #[test]
+    fn zero_seed_still_mutates() {
+        let rules = rules(&["0: 1 | 2", "1: \"a\"", "2: \"b\""]);
+        let mut generator = generator::Generator::new(&rules, 5, 0).unwrap();
+        let generated: HashSet<String> = (0..20).map(|_| generator.generate(0).unwrap()).collect();
+        assert!(generated.len() > 1);
+        assert_ne!(generator.mutate("ab"), "ab");
+    }
+
+    #[test]
+    fn regex_matches_whole_messages() {
+        // a leftmost-first search stops after the shorter alternative
+        let rules = rules(&["0: 1 | 2", "1: \"a\"", "2: \"aa\""]);
+        let regex = build_regex(&rules, 0).unwrap();
+        assert!(regex.is_match("a"));
+        assert!(regex.is_match("aa"));
+        assert!(!regex.is_match("aaa"));
+    }
+}
@@ -1,9 +1,13 @@
 use std::fmt::{Debug, Display, Formatter, Write};
 use adventofcode2020::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
 struct Tile {
     id: u64,
-    data: Vec<Vec<u8>>,
+    /// width and height in pixels, at most 64 so borders fit into the masks
+    size: usize,
+    data: Image,
     border_masks: [u64; 4],
 }
 
@@ -13,32 +17,105 @@ fn to_mask(bytes: impl Iterator<Item=u8>) -> u64 {
     })
 }
 
+fn reverse_mask(mask: u64, size: usize) -> u64 {
+    mask.reverse_bits() >> (64 - size)
+}
+
 impl Tile {
-    fn new(id: u64, data: Vec<Vec<u8>>) -> Self {
-        let top = to_mask(data[0].iter().copied());
-        let bottom = to_mask(data[data.len()-1].iter().copied());
-        let left = to_mask(data.iter().map(|row| row[0]));
-        let right = to_mask(data.iter().map(|row| row[row.len()-1]));
+    fn new(id: u64, data: Image) -> Result<Self> {
+        let size = data.height();
+        if data.width() != size {
+            return Err(Error::General(format!("Tile {} is not square", id)));
+        }
+        if size > 64 {
+            return Err(Error::General(format!(
+                "Tile {} has {} pixels per side, at most 64 are supported",
+                id, size
+            )));
+        }
 
-        Self {
+        let borders = data.borders();
+        let mask = |i: usize| to_mask(borders[i].iter().copied());
+
+        Ok(Self {
             id,
+            size,
             data,
-            border_masks: [top, right, bottom, left]
-        }
+            border_masks: [mask(0), mask(1), mask(2), mask(3)]
+        })
+    }
+
+    /// The border mask read in the opposite direction.
+    fn reverse(&self, mask: u64) -> u64 {
+        reverse_mask(mask, self.size)
+    }
+
+    /// The same for a border and its reverse.
+    fn canonical(&self, mask: u64) -> u64 {
+        mask.min(self.reverse(mask))
     }
 }
 
-impl Display for Tile {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("Tile {:04}:\n", self.id))?;
-        for row in self.data.iter() {
-            for byte in row.iter() {
-                f.write_char(*byte as char)?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Corner,
+    Edge,
+    Interior,
+}
+
+/// Tile ids by canonical border, so neighbours are found without comparing all tiles.
+struct BorderIndex {
+    tiles: HashMap<u64, Vec<u64>>,
+}
+
+impl BorderIndex {
+    fn new(tiles: &[Tile]) -> Self {
+        let mut index: HashMap<u64, Vec<u64>> = HashMap::new();
+        for tile in tiles {
+            for mask in tile.border_masks.iter() {
+                let ids = index.entry(tile.canonical(*mask)).or_default();
+                // a tile with two equal borders is listed once
+                if ids.last() != Some(&tile.id) {
+                    ids.push(tile.id);
+                }
             }
-            f.write_str("\n")?;
         }
+        Self { tiles: index }
+    }
 
-        Ok(())
+    /// Ids of the other tiles having the border `mask` of `tile` in either direction.
+    fn others<'a>(&'a self, tile: &'a Tile, mask: u64) -> impl Iterator<Item = u64> + 'a {
+        self.tiles
+            .get(&tile.canonical(mask))
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |id| *id != tile.id)
+    }
+
+    /// Number of borders no other tile shares.
+    fn outer_borders(&self, tile: &Tile) -> usize {
+        tile.border_masks
+            .iter()
+            .filter(|mask| self.others(tile, **mask).next().is_none())
+            .count()
+    }
+
+    /// `None` for tiles with more than two outer borders, which do not fit a square of
+    /// at least two by two tiles.
+    fn kind(&self, tile: &Tile) -> Option<Kind> {
+        match self.outer_borders(tile) {
+            0 => Some(Kind::Interior),
+            1 => Some(Kind::Edge),
+            2 => Some(Kind::Corner),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Tile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Tile {:04}:\n{}\n", self.id, self.data))
     }
 }
 
@@ -47,7 +124,7 @@ impl Debug for Tile {
         f.write_fmt(format_args!("{}", self))?;
         f.write_str("Borders: [\n")?;
         for mask in self.border_masks.iter() {
-            f.write_fmt(format_args!("  {:010b}\n", mask))?;
+            f.write_fmt(format_args!("  {:01$b}\n", mask, self.size))?;
         }
         f.write_str("]")?;
 
@@ -55,32 +132,846 @@ impl Debug for Tile {
     }
 }
 
-pub fn main() -> Result<()> {
-    let lines: Vec<String> = read_file("data/20.txt")?;
-
+/// Reads the `Tile NNNN:` blocks, all tiles have to be of the same size.
+fn parse_tiles(lines: &[String]) -> Result<Vec<Tile>> {
     let tiles = lines.split(|line| line.is_empty()).map(|tile| {
         if let Some((header, data)) = tile.split_first() {
             let id = header["Tile ".len()..header.len()-1].parse::<u64>()?;
-            Ok(Tile::new(id, data.iter().map(|s| s.clone().into_bytes()).collect()))
+            Tile::new(id, data.join("\n").parse()?)
         } else {
             Err(Error::General("Empty tile".into()))
         }
     }).collect::<Result<Vec<_>>>()?;
 
-    let corner_tiles =  tiles.iter().filter(|tile| {
-        let count_outer_borders = tile.border_masks.iter().filter(|mask| {
-            !tiles.iter().any(|other_tile| !std::ptr::eq(*tile, other_tile) && (other_tile.border_masks.contains(*mask)) ^ other_tile.border_masks.contains(&(mask.reverse_bits() >> 54)))
-        }).count();
-        count_outer_borders == 2
-    }).collect::<Vec<_>>();
+    if let Some(tile) = tiles.iter().find(|tile| tile.size != tiles[0].size) {
+        return Err(Error::General(format!(
+            "Tile {} has {} pixels per side, tile {} has {}",
+            tile.id, tile.size, tiles[0].id, tiles[0].size
+        )));
+    }
 
-    dbg!(&corner_tiles);
+    Ok(tiles)
+}
+
+/// A rectangular grid of `#` and `.` pixels.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Image {
+    rows: Vec<Vec<u8>>,
+}
+
+impl Image {
+    fn new(rows: Vec<Vec<u8>>) -> Result<Self> {
+        if rows.is_empty() || rows[0].is_empty() {
+            return Err(Error::General("Empty image".into()));
+        }
+        if rows.iter().any(|row| row.len() != rows[0].len()) {
+            return Err(Error::General("Image rows have different widths".into()));
+        }
+        Ok(Self { rows })
+    }
+
+    fn width(&self) -> usize {
+        self.rows[0].len()
+    }
+
+    fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Rotates the image clockwise.
+    fn rotate90(&self) -> Self {
+        let rows = (0..self.width())
+            .map(|x| self.rows.iter().rev().map(|row| row[x]).collect())
+            .collect();
+        Self { rows }
+    }
+
+    /// Mirrors the image left to right.
+    fn flip_h(&self) -> Self {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| row.iter().rev().copied().collect())
+            .collect();
+        Self { rows }
+    }
+
+    /// Mirrors the image top to bottom.
+    fn flip_v(&self) -> Self {
+        let rows = self.rows.iter().rev().cloned().collect();
+        Self { rows }
+    }
+
+    /// The 4 rotations of the image followed by the 4 rotations of its mirror image.
+    fn orientations(&self) -> Vec<Self> {
+        // a half turn only reverses the rows and columns, so just the quarter turns rotate
+        let half_turn = |image: &Self| image.flip_h().flip_v();
+        let quarter = self.rotate90();
+        let mirror = self.flip_h();
+        let mirror_quarter = mirror.rotate90();
+        vec![
+            self.clone(),
+            quarter.clone(),
+            half_turn(self),
+            half_turn(&quarter),
+            mirror.clone(),
+            mirror_quarter.clone(),
+            half_turn(&mirror),
+            half_turn(&mirror_quarter),
+        ]
+    }
+
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Result<Self> {
+        if x + width > self.width() || y + height > self.height() {
+            return Err(Error::General(format!(
+                "Can not crop {}x{} at {},{} from a {}x{} image",
+                width,
+                height,
+                x,
+                y,
+                self.width(),
+                self.height()
+            )));
+        }
+        Self::new(
+            self.rows[y..y + height]
+                .iter()
+                .map(|row| row[x..x + width].to_vec())
+                .collect(),
+        )
+    }
+
+    /// The image without its outermost pixels.
+    fn strip_border(&self) -> Result<Self> {
+        let (width, height) = (self.width(), self.height());
+        self.crop(1, 1, width.saturating_sub(2), height.saturating_sub(2))
+    }
+
+    /// Joins a grid of images, the images in a row need the same height and all rows the
+    /// same total width.
+    fn stitch(grid: &[Vec<Image>]) -> Result<Self> {
+        let mut rows = vec![];
+        for images in grid {
+            let height = images.first().map_or(0, |image| image.height());
+            if images.iter().any(|image| image.height() != height) {
+                return Err(Error::General("Stitched images differ in height".into()));
+            }
+            for y in 0..height {
+                rows.push(
+                    images
+                        .iter()
+                        .flat_map(|image| image.rows[y].iter().copied())
+                        .collect(),
+                );
+            }
+        }
+        Self::new(rows)
+    }
+
+    /// Top, right, bottom and left border, read left to right and top to bottom.
+    fn borders(&self) -> [Vec<u8>; 4] {
+        [
+            self.rows[0].clone(),
+            self.rows.iter().map(|row| row[row.len() - 1]).collect(),
+            self.rows[self.rows.len() - 1].clone(),
+            self.rows.iter().map(|row| row[0]).collect(),
+        ]
+    }
+}
+
+impl Display for Image {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                f.write_char('\n')?;
+            }
+            for byte in row.iter() {
+                f.write_char(*byte as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Image {
+    type Err = Error;
 
-    // eprintln!("{:010b}", u64::from_str_radix("1101010000", 2).unwrap().reverse_bits() >> 54);
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(ch) = s.chars().find(|ch| !"#.\n".contains(*ch)) {
+            return Err(Error::General(format!("Invalid pixel {:?}", ch)));
+        }
+        Self::new(s.lines().map(|line| line.as_bytes().to_vec()).collect())
+    }
+}
+
+struct Assembly {
+    /// tile ids in their positions
+    layout: Vec<Vec<u64>>,
+    /// the oriented tiles stitched together without their borders
+    image: Image,
+}
+
+/// Arranges the tiles into a square so that neighbouring borders are equal. Starts with a
+/// corner in the top left and fills row by row, every position has to be filled by
+/// exactly one remaining tile in exactly one orientation.
+fn assemble(tiles: &[Tile]) -> Result<Assembly> {
+    let side = (1..=tiles.len())
+        .find(|side| side * side >= tiles.len())
+        .unwrap_or(0);
+    if side == 0 || side * side != tiles.len() {
+        return Err(Error::General(format!(
+            "{} tiles do not form a square",
+            tiles.len()
+        )));
+    }
+
+    let index = BorderIndex::new(tiles);
+    let positions: HashMap<u64, usize> = tiles
+        .iter()
+        .enumerate()
+        .map(|(position, tile)| (tile.id, position))
+        .collect();
+    let unmatched = |position: usize, border: &Vec<u8>| {
+        let mask = to_mask(border.iter().copied());
+        index.others(&tiles[position], mask).next().is_none()
+    };
+
+    let corners: Vec<usize> = (0..tiles.len())
+        .filter(|&position| index.kind(&tiles[position]) == Some(Kind::Corner))
+        .collect();
+    if side > 1 && corners.len() != 4 {
+        return Err(Error::General(format!(
+            "Expected 4 corner tiles, found {}",
+            corners.len()
+        )));
+    }
+
+    let first = corners.first().copied().unwrap_or(0);
+    let oriented = tiles[first]
+        .data
+        .orientations()
+        .into_iter()
+        .find(|data| {
+            let [top, _, _, left] = data.borders();
+            side == 1 || (unmatched(first, &top) && unmatched(first, &left))
+        })
+        .ok_or_else(|| Error::General(format!("Tile {} has no free corner", tiles[first].id)))?;
+
+    let mut used = vec![false; tiles.len()];
+    used[first] = true;
+    let mut grid: Vec<Vec<(usize, Image)>> = vec![vec![(first, oriented)]];
+    for row in 0..side {
+        for col in 0..side {
+            if row == 0 && col == 0 {
+                continue;
+            }
+            if col == 0 {
+                grid.push(vec![]);
+            }
+            let left = if col > 0 {
+                Some(grid[row][col - 1].1.borders()[1].clone())
+            } else {
+                None
+            };
+            let top = if row > 0 {
+                Some(grid[row - 1][col].1.borders()[2].clone())
+            } else {
+                None
+            };
+
+            // only the tiles sharing the border with the left or top neighbour can fit
+            let (neighbour, border) = match (&left, &top) {
+                (Some(left), _) => (grid[row][col - 1].0, left),
+                (None, Some(top)) => (grid[row - 1][col].0, top),
+                (None, None) => unreachable!(),
+            };
+            let mask = to_mask(border.iter().copied());
+            let mut candidates: Vec<(usize, Image)> = index
+                .others(&tiles[neighbour], mask)
+                .map(|id| positions[&id])
+                .filter(|position| !used[*position])
+                .flat_map(|position| {
+                    tiles[position]
+                        .data
+                        .orientations()
+                        .into_iter()
+                        .map(move |data| (position, data))
+                })
+                .filter(|(_, data)| {
+                    let borders = data.borders();
+                    left.as_ref().map_or(true, |left| *left == borders[3])
+                        && top.as_ref().map_or(true, |top| *top == borders[0])
+                })
+                .collect();
+            // orientations of a symmetric tile can be the same image
+            candidates.sort();
+            candidates.dedup();
+
+            match candidates.len() {
+                0 => {
+                    return Err(Error::General(format!(
+                        "No tile fits at row {} column {}",
+                        row, col
+                    )))
+                }
+                1 => {}
+                _ => {
+                    let ids: Vec<String> = candidates
+                        .iter()
+                        .map(|(position, _)| tiles[*position].id.to_string())
+                        .collect();
+                    return Err(Error::General(format!(
+                        "Ambiguous match at row {} column {}: {}",
+                        row,
+                        col,
+                        ids.join(", ")
+                    )));
+                }
+            }
+            let (position, data) = candidates.pop().unwrap();
+            used[position] = true;
+            grid[row].push((position, data));
+        }
+    }
+
+    let layout = grid
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|(position, _)| tiles[*position].id)
+                .collect()
+        })
+        .collect();
+    let image = Image::stitch(
+        &grid
+            .iter()
+            .map(|row| row.iter().map(|(_, data)| data.strip_border()).collect())
+            .collect::<Result<Vec<_>>>()?,
+    )?;
+
+    Ok(Assembly { layout, image })
+}
+
+const SEA_MONSTER: &str = "                  # \n#    ##    ##    ###\n #  #  #  #  #  #   ";
+
+/// Reads a template where `#` cells have to be set and any other character matches anything.
+fn parse_template(s: &str) -> Result<Image> {
+    let lines: Vec<&str> = s.lines().collect();
+    let width = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+    if !lines.iter().any(|line| line.contains('#')) {
+        return Err(Error::General("Template has no '#'".into()));
+    }
+    Image::new(
+        lines
+            .iter()
+            .map(|line| {
+                let mut row: Vec<u8> = line
+                    .bytes()
+                    .map(|b| if b == b'#' { b'#' } else { b'.' })
+                    .collect();
+                row.resize(width, b'.');
+                row
+            })
+            .collect(),
+    )
+}
+
+/// The `#` cells of every occurrence of the template in the image. Searching with all
+/// orientations of the template finds the occurrences in all orientations of the image.
+fn find_pattern(image: &Image, template: &Image) -> Vec<Vec<(usize, usize)>> {
+    let mut templates = template.orientations();
+    templates.sort();
+    // symmetric templates would find the same occurrence several times
+    templates.dedup();
+
+    let mut occurrences = vec![];
+    for template in templates.iter() {
+        let cells: Vec<(usize, usize)> = template
+            .rows
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row.iter()
+                    .enumerate()
+                    .filter(|(_, b)| **b == b'#')
+                    .map(move |(x, _)| (y, x))
+            })
+            .collect();
+        let (height, width) = (template.height(), template.width());
+        if image.height() < height || image.width() < width {
+            continue;
+        }
+        for y in 0..=image.height() - height {
+            for x in 0..=image.width() - width {
+                if cells
+                    .iter()
+                    .all(|(dy, dx)| image.rows[y + dy][x + dx] == b'#')
+                {
+                    occurrences.push(cells.iter().map(|(dy, dx)| (y + dy, x + dx)).collect());
+                }
+            }
+        }
+    }
+    occurrences
+}
+
+/// Number of `#` cells not covered by any occurrence.
+fn roughness(image: &Image, occurrences: &[Vec<(usize, usize)>]) -> usize {
+    highlight(image, occurrences)
+        .rows
+        .iter()
+        .flatten()
+        .filter(|b| **b == b'#')
+        .count()
+}
+
+/// The image with the cells of all occurrences replaced by `O`.
+fn highlight(image: &Image, occurrences: &[Vec<(usize, usize)>]) -> Image {
+    let mut highlighted = image.clone();
+    for (y, x) in occurrences.iter().flatten() {
+        highlighted.rows[*y][*x] = b'O';
+    }
+    highlighted
+}
+
+/// A generated puzzle with the arrangement it was cut from.
+struct Puzzle {
+    tiles: Vec<Tile>,
+    /// tile ids in their original positions
+    layout: Vec<Vec<u64>>,
+}
+
+impl Display for Puzzle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, tile) in self.tiles.iter().enumerate() {
+            if i > 0 {
+                f.write_char('\n')?;
+            }
+            f.write_fmt(format_args!("{}", tile))?;
+        }
+        Ok(())
+    }
+}
+
+impl Image {
+    fn noise(width: usize, height: usize, rng: &mut XorShift) -> Result<Self> {
+        Self::new(
+            (0..height)
+                .map(|_| {
+                    (0..width)
+                        .map(|_| if rng.below(2) == 0 { b'.' } else { b'#' })
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Cuts `image` into tiles of `size` pixels whose interiors are blocks of the image, with
+/// random borders unique up to reversal and shared by neighbouring tiles. `ambiguous` outer
+/// borders are then replaced by copies of inner ones with the same corner pixels. The tiles
+/// are randomly rotated and flipped, get random ids and are shuffled.
+fn generate(image: &Image, size: usize, ambiguous: usize, rng: &mut XorShift) -> Result<Puzzle> {
+    let inner = size.saturating_sub(2);
+    if inner == 0 || size > 64 {
+        return Err(Error::General(format!(
+            "Can not generate tiles of size {}",
+            size
+        )));
+    }
+    let side = image.width() / inner;
+    if image.width() != image.height() || side * inner != image.width() {
+        return Err(Error::General(format!(
+            "A {}x{} image can not be cut into square tiles of size {}",
+            image.width(),
+            image.height(),
+            size
+        )));
+    }
+    if ambiguous > 0 && side < 2 {
+        return Err(Error::General("A single tile has no inner borders".into()));
+    }
+
+    // neighbouring tiles overlap in their borders on the canvas
+    let step = size - 1;
+    let len = side * step + 1;
+    let mut canvas = vec![vec![b'.'; len]; len];
+    for (y, row) in image.rows.iter().enumerate() {
+        for (x, pixel) in row.iter().enumerate() {
+            canvas[y / inner * step + 1 + y % inner][x / inner * step + 1 + x % inner] = *pixel;
+        }
+    }
+    for y in (0..len).step_by(step) {
+        for x in (0..len).step_by(step) {
+            canvas[y][x] = if rng.below(2) == 0 { b'.' } else { b'#' };
+        }
+    }
+
+    // the pixels of every horizontal and vertical border on the canvas
+    let mut borders: Vec<Vec<(usize, usize)>> = vec![];
+    for i in 0..=side {
+        for j in 0..side {
+            borders.push((0..size).map(|k| (i * step, j * step + k)).collect());
+            borders.push((0..size).map(|k| (j * step + k, i * step)).collect());
+        }
+    }
+    let mut used = HashSet::new();
+    for border in borders.iter() {
+        let unique = (0..1000).any(|_| {
+            for (y, x) in border[1..size - 1].iter() {
+                canvas[*y][*x] = if rng.below(2) == 0 { b'.' } else { b'#' };
+            }
+            let mask = to_mask(border.iter().map(|(y, x)| canvas[*y][*x]));
+            let reversed = reverse_mask(mask, size);
+            // palindromes would fit in two orientations
+            mask != reversed && used.insert(mask.min(reversed))
+        });
+        if !unique {
+            return Err(Error::General(format!(
+                "Not enough unique borders for tiles of size {}",
+                size
+            )));
+        }
+    }
+
+    let outer = |border: &Vec<(usize, usize)>| {
+        border.iter().all(|(y, _)| *y == 0 || *y == len - 1)
+            || border.iter().all(|(_, x)| *x == 0 || *x == len - 1)
+    };
+    let (outer, inner): (Vec<_>, Vec<_>) = borders.iter().partition(|border| outer(border));
+    for _ in 0..ambiguous {
+        // the corner pixels are shared with the neighbouring borders and stay as they are
+        let to = outer[rng.below(outer.len())];
+        let corners = |border: &[(usize, usize)]| {
+            let (first, last) = (border[0], border[size - 1]);
+            (canvas[first.0][first.1], canvas[last.0][last.1])
+        };
+        let fitting: Vec<Vec<(usize, usize)>> = inner
+            .iter()
+            .flat_map(|from| vec![from.to_vec(), from.iter().rev().copied().collect()])
+            .filter(|from| corners(from) == corners(to))
+            .collect();
+        if fitting.is_empty() {
+            return Err(Error::General(
+                "No inner border has the corner pixels of an outer one".into(),
+            ));
+        }
+        let from = &fitting[rng.below(fitting.len())];
+        for ((y, x), (from_y, from_x)) in to[1..size - 1].iter().zip(from[1..size - 1].iter()) {
+            canvas[*y][*x] = canvas[*from_y][*from_x];
+        }
+    }
+
+    let canvas = Image::new(canvas)?;
+    let mut ids = HashSet::new();
+    let mut layout = vec![];
+    let mut tiles = vec![];
+    for row in 0..side {
+        layout.push(vec![]);
+        for col in 0..side {
+            let id = loop {
+                let id = 1000 + rng.below(9000.max(side * side * 10)) as u64;
+                if ids.insert(id) {
+                    break id;
+                }
+            };
+            let data = canvas.crop(col * step, row * step, size, size)?;
+            let data = data.orientations().swap_remove(rng.below(8));
+            layout[row].push(id);
+            tiles.push(Tile::new(id, data)?);
+        }
+    }
+    for i in (1..tiles.len()).rev() {
+        tiles.swap(i, rng.below(i + 1));
+    }
+
+    Ok(Puzzle { tiles, layout })
+}
+
+/// One line of tile ids per row.
+fn format_layout(layout: &[Vec<u64>]) -> String {
+    let mut formatted = String::new();
+    for row in layout.iter() {
+        let ids: Vec<String> = row.iter().map(|id| format!("{:04}", id)).collect();
+        formatted.push_str(&ids.join(" "));
+        formatted.push('\n');
+    }
+    formatted
+}
+
+pub fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("generate") {
+        // generate <tile size> <tiles per side> [ambiguous borders] [layout file]
+        args.next();
+        let mut number = |default: Option<usize>| -> Result<usize> {
+            match args.next() {
+                Some(arg) => Ok(arg.parse()?),
+                None => default.ok_or_else(|| Error::General("Missing argument".into())),
+            }
+        };
+        let size = number(None)?;
+        let side = number(None)?;
+        let ambiguous = number(Some(0))?;
+        let mut rng = XorShift::new(0x2020);
+        let width = side * size.saturating_sub(2);
+        let noise = Image::noise(width, width, &mut rng)?;
+        let puzzle = generate(&noise, size, ambiguous, &mut rng)?;
+        // the arrangement the tiles were cut from, to compare assemblies with
+        if let Some(path) = args.next() {
+            std::fs::write(path, format_layout(&puzzle.layout))?;
+        }
+        println!("{}", puzzle);
+        return Ok(());
+    }
+
+    let lines: Vec<String> = read_file("data/20.txt")?;
+
+    let tiles = parse_tiles(&lines)?;
+
+    let index = BorderIndex::new(&tiles);
+    let corner_tiles = tiles
+        .iter()
+        .filter(|tile| index.kind(tile) == Some(Kind::Corner))
+        .collect::<Vec<_>>();
+
+    dbg!(&corner_tiles);
 
     let part1 = corner_tiles.iter().map(|tile| tile.id).product::<u64>();
 
     println!("Part1: {}", part1);
 
+    let assembly = assemble(&tiles)?;
+    print!("{}", format_layout(&assembly.layout));
+
+    // optional template and image files, the sea monster and the assembled image otherwise
+    let template = match args.next() {
+        Some(path) => parse_template(&std::fs::read_to_string(path)?)?,
+        None => parse_template(SEA_MONSTER)?,
+    };
+    let image = match args.next() {
+        Some(path) => std::fs::read_to_string(path)?.parse()?,
+        None => assembly.image,
+    };
+
+    let occurrences = find_pattern(&image, &template);
+    println!("{}", highlight(&image, &occurrences));
+    println!("{} occurrences", occurrences.len());
+
+    println!("Part2: {}", roughness(&image, &occurrences));
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn puzzle() -> Vec<Tile> {
+        let lines: Vec<String> = read_file("data/20.txt").unwrap();
+        parse_tiles(&lines).unwrap()
+    }
+
+    /// All pairs of horizontally or vertically adjacent ids.
+    fn neighbours(layout: &[Vec<u64>]) -> HashSet<(u64, u64)> {
+        let mut pairs = HashSet::new();
+        for (y, row) in layout.iter().enumerate() {
+            for (x, id) in row.iter().enumerate() {
+                let right = row.get(x + 1);
+                let below = layout.get(y + 1).map(|row| &row[x]);
+                for other in right.into_iter().chain(below) {
+                    pairs.insert((*id.min(other), *id.max(other)));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Number of sides of every tile at the edge of the layout.
+    fn outer_sides(layout: &[Vec<u64>]) -> HashMap<u64, usize> {
+        let last = layout.len() - 1;
+        let mut sides = HashMap::new();
+        for (y, row) in layout.iter().enumerate() {
+            for (x, id) in row.iter().enumerate() {
+                let outer = [y == 0, y == last, x == 0, x == last];
+                sides.insert(*id, outer.iter().filter(|outer| **outer).count());
+            }
+        }
+        sides
+    }
+
+    /// Prints a generated puzzle as text and reads the tiles back.
+    fn reparse(puzzle: &Puzzle) -> Vec<Tile> {
+        let lines: Vec<String> = puzzle.to_string().lines().map(String::from).collect();
+        parse_tiles(&lines).unwrap()
+    }
+
+    /// Generates a puzzle and assembles it back into the image it was cut from.
+    fn check_generated(image: &Image, size: usize, rng: &mut XorShift) {
+        let puzzle = generate(image, size, 0, rng).unwrap();
+        let tiles = reparse(&puzzle);
+        let index = BorderIndex::new(&tiles);
+        let sides = outer_sides(&puzzle.layout);
+        for tile in tiles.iter() {
+            assert_eq!(index.outer_borders(tile), sides[&tile.id]);
+        }
+
+        let assembly = assemble(&tiles).unwrap();
+        assert!(image.orientations().contains(&assembly.image));
+        // the same neighbours mean the layout is only rotated or flipped
+        assert_eq!(neighbours(&assembly.layout), neighbours(&puzzle.layout));
+    }
+
+    #[test]
+    fn generated_puzzles_assemble() {
+        let mut rng = XorShift::new(0x2020);
+        check_generated(&assemble(&puzzle()).unwrap().image, 10, &mut rng);
+        for size in &[8, 12, 64] {
+            let noise = Image::noise(4 * (size - 2), 4 * (size - 2), &mut rng).unwrap();
+            check_generated(&noise, *size, &mut rng);
+        }
+        assert!(Tile::new(1, Image::noise(65, 65, &mut rng).unwrap()).is_err());
+    }
+
+    #[test]
+    fn large_generated_puzzle_assembles() {
+        // 22500 tiles, larger tiles leave enough room for unique borders
+        let mut rng = XorShift::new(0x2020);
+        let noise = Image::noise(150 * 18, 150 * 18, &mut rng).unwrap();
+        check_generated(&noise, 20, &mut rng);
+    }
+
+    #[test]
+    fn ambiguous_borders() {
+        let image = assemble(&puzzle()).unwrap().image;
+        let mut rng = XorShift::new(0x2020);
+        for _ in 0..20 {
+            let puzzle = generate(&image, 10, 1, &mut rng).unwrap();
+            let tiles = reparse(&puzzle);
+            let index = BorderIndex::new(&tiles);
+            let sides = outer_sides(&puzzle.layout);
+            // exactly one tile lost one of its outer borders
+            let affected: Vec<&Tile> = tiles
+                .iter()
+                .filter(|tile| index.outer_borders(tile) != sides[&tile.id])
+                .collect();
+            assert_eq!(affected.len(), 1);
+            let affected = affected[0];
+            assert_eq!(index.outer_borders(affected) + 1, sides[&affected.id]);
+
+            let mut corners: Vec<u64> = tiles
+                .iter()
+                .filter(|tile| index.kind(tile) == Some(Kind::Corner))
+                .map(|tile| tile.id)
+                .collect();
+            corners.sort_unstable();
+            let mut expected: Vec<u64> = sides
+                .iter()
+                .filter(|(id, sides)| **sides == 2 && **id != affected.id)
+                .map(|(id, _)| *id)
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(corners, expected);
+
+            match assemble(&tiles) {
+                // a corner turned into an edge
+                Err(e) if sides[&affected.id] == 2 => {
+                    assert_eq!(e.to_string(), "General: Expected 4 corner tiles, found 3")
+                }
+                // the copy only competes where a single neighbour constrains the tile
+                Err(e) => assert!(e.to_string().contains("Ambiguous match"), "{}", e),
+                Ok(assembly) => {
+                    assert_eq!(sides[&affected.id], 1);
+                    assert!(image.orientations().contains(&assembly.image));
+                    assert_eq!(neighbours(&assembly.layout), neighbours(&puzzle.layout));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orientations_form_a_dihedral_group() {
+        // the 8 orientations of an asymmetric image are distinct and the transforms map
+        // them onto each other
+        let image = assemble(&puzzle()).unwrap().image;
+        let orientations = image.orientations();
+        let distinct: HashSet<&Image> = orientations.iter().collect();
+        assert_eq!(distinct.len(), 8);
+        for image in orientations.iter() {
+            for transformed in &[image.rotate90(), image.flip_h(), image.flip_v()] {
+                assert!(distinct.contains(transformed));
+            }
+        }
+    }
+
+    #[test]
+    fn image_survives_printing() {
+        let image = assemble(&puzzle()).unwrap().image;
+        assert_eq!(image.to_string().parse::<Image>().unwrap(), image);
+    }
+
+    #[test]
+    fn sea_monsters() {
+        let image = assemble(&puzzle()).unwrap().image;
+        let occurrences = find_pattern(&image, &parse_template(SEA_MONSTER).unwrap());
+        assert_eq!(occurrences.len(), 16);
+        assert!(occurrences.iter().all(|cells| cells.len() == 15));
+        assert_eq!(roughness(&image, &occurrences), 2495);
+    }
+
+    #[test]
+    fn rotated_template() {
+        // the L only matches rotated by 90 degrees
+        let image: Image = ".##\n..#\n#..".parse().unwrap();
+        let template = parse_template("#\n##").unwrap();
+        assert_eq!(template.to_string(), "#.\n##");
+        let mut occurrences = find_pattern(&image, &template);
+        assert_eq!(occurrences.len(), 1);
+        occurrences[0].sort_unstable();
+        assert_eq!(occurrences[0], vec![(0, 1), (0, 2), (1, 2)]);
+        assert_eq!(highlight(&image, &occurrences).to_string(), ".OO\n..O\n#..");
+        assert_eq!(roughness(&image, &occurrences), 1);
+    }
+
+    #[test]
+    fn symmetric_template_matches_once() {
+        // the four rotations of the domino are two distinct templates, the mirror images
+        // repeat them
+        let image: Image = "##..\n..#.\n..#.\n#...".parse().unwrap();
+        let occurrences = find_pattern(&image, &parse_template("##").unwrap());
+        assert_eq!(occurrences.len(), 2);
+        assert_eq!(
+            highlight(&image, &occurrences).to_string(),
+            "OO..\n..O.\n..O.\n#..."
+        );
+        assert_eq!(roughness(&image, &occurrences), 1);
+        assert!(parse_template("  \n..").is_err());
+    }
+
+    #[test]
+    fn symmetric_tiles_fit_once() {
+        // the bottom right tile is symmetric along its diagonal, so two of its orientations
+        // are the same image
+        let mut rng = XorShift::new(0x2020);
+        let size = 10;
+        let step = size - 1;
+        let mut canvas = Image::noise(2 * step + 1, 2 * step + 1, &mut rng).unwrap();
+        for y in step..=2 * step {
+            for x in step..y {
+                canvas.rows[y][x] = canvas.rows[x][y];
+            }
+        }
+        let mut tiles = vec![];
+        for row in 0..2 {
+            for col in 0..2 {
+                let data = canvas.crop(col * step, row * step, size, size).unwrap();
+                tiles.push(Tile::new(1 + 2 * row as u64 + col as u64, data).unwrap());
+            }
+        }
+        let assembly = assemble(&tiles).unwrap();
+        assert_eq!(
+            neighbours(&assembly.layout),
+            neighbours(&[vec![1, 2], vec![3, 4]])
+        );
+    }
+}
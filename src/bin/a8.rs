@@ -1,7 +1,9 @@
 use adventofcode2020::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Instruction {
     Nop(i32),
     Acc(i32),
@@ -31,6 +33,151 @@ impl FromStr for Instruction {
     }
 }
 
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Nop(a) => f.write_fmt(format_args!("nop {:+}", a)),
+            Instruction::Acc(a) => f.write_fmt(format_args!("acc {:+}", a)),
+            Instruction::Jmp(a) => f.write_fmt(format_args!("jmp {:+}", a)),
+        }
+    }
+}
+
+mod bytecode {
+    //! Binary encoding of programs.
+    //!
+    //! Layout: the magic bytes `HHBC`, a version byte, the adler-32 checksum of the body as
+    //! little-endian `u32`, the instruction count as varint and then the body. Every instruction
+    //! in the body is an opcode byte followed by its operand as zigzag encoded varint.
+
+    use super::Error;
+    use super::Instruction;
+    use super::Result;
+
+    const MAGIC: &[u8; 4] = b"HHBC";
+    const VERSION: u8 = 1;
+    const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+    const OP_NOP: u8 = 0;
+    const OP_ACC: u8 = 1;
+    const OP_JMP: u8 = 2;
+
+    fn adler32(bytes: &[u8]) -> u32 {
+        let (a, b) = bytes.iter().fold((1_u32, 0_u32), |(a, b), byte| {
+            let a = (a + *byte as u32) % 65521;
+            let b = (b + a) % 65521;
+            (a, b)
+        });
+        (b << 16) | a
+    }
+
+    fn write_varint(mut value: u64, output: &mut Vec<u8>) {
+        while value >= 0x80 {
+            output.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        output.push(value as u8);
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+        let mut value = 0_u64;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes
+                .get(*pos)
+                .ok_or_else(|| Error::General("Unexpected end of bytecode in varint".into()))?;
+            *pos += 1;
+            if shift >= 64 || (shift == 63 && byte > 1) {
+                return Err(Error::General("Varint overflow".into()));
+            }
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn zigzag(value: i32) -> u64 {
+        ((value << 1) ^ (value >> 31)) as u32 as u64
+    }
+
+    fn unzigzag(value: u64) -> Result<i32> {
+        if value > u32::MAX as u64 {
+            return Err(Error::General(format!("Operand {} out of range", value)));
+        }
+        let value = value as u32;
+        Ok(((value >> 1) as i32) ^ -((value & 1) as i32))
+    }
+
+    pub(super) fn encode(code: &[Instruction]) -> Vec<u8> {
+        let mut body = vec![];
+        write_varint(code.len() as u64, &mut body);
+        code.iter().for_each(|insn| {
+            let (op, arg) = match insn {
+                Instruction::Nop(a) => (OP_NOP, *a),
+                Instruction::Acc(a) => (OP_ACC, *a),
+                Instruction::Jmp(a) => (OP_JMP, *a),
+            };
+            body.push(op);
+            write_varint(zigzag(arg), &mut body);
+        });
+
+        let mut output = Vec::with_capacity(HEADER_LEN + body.len());
+        output.extend_from_slice(MAGIC);
+        output.push(VERSION);
+        output.extend_from_slice(&adler32(&body).to_le_bytes());
+        output.extend_from_slice(&body);
+        output
+    }
+
+    pub(super) fn decode(bytes: &[u8]) -> Result<Vec<Instruction>> {
+        if bytes.len() < HEADER_LEN || &bytes[0..MAGIC.len()] != MAGIC {
+            return Err(Error::General("Missing bytecode header".into()));
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(Error::General(format!(
+                "Unsupported bytecode version {}",
+                version
+            )));
+        }
+        let mut checksum = [0_u8; 4];
+        checksum.copy_from_slice(&bytes[MAGIC.len() + 1..HEADER_LEN]);
+        let body = &bytes[HEADER_LEN..];
+        if u32::from_le_bytes(checksum) != adler32(body) {
+            return Err(Error::General("Bytecode checksum mismatch".into()));
+        }
+
+        let mut pos = 0;
+        let len = read_varint(body, &mut pos)? as usize;
+        // every instruction takes at least two bytes
+        let mut code = Vec::with_capacity(len.min(body.len() / 2));
+        for _ in 0..len {
+            let op = *body
+                .get(pos)
+                .ok_or_else(|| Error::General("Unexpected end of bytecode".into()))?;
+            pos += 1;
+            let arg = unzigzag(read_varint(body, &mut pos)?)?;
+            code.push(match op {
+                OP_NOP => Instruction::Nop(arg),
+                OP_ACC => Instruction::Acc(arg),
+                OP_JMP => Instruction::Jmp(arg),
+                _ => return Err(Error::General(format!("Invalid opcode {}", op))),
+            });
+        }
+
+        if pos != body.len() {
+            return Err(Error::General(format!(
+                "{} trailing bytes after bytecode",
+                body.len() - pos
+            )));
+        }
+
+        Ok(code)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct CPU {
     code: Vec<Instruction>,
@@ -38,6 +185,26 @@ struct CPU {
     acc: i64,
     counters: Vec<i32>,
     corrupted_insn: usize,
+    // keep running past revisited instructions until this many steps were executed
+    step_budget: Option<usize>,
+    // executed instructions with the accumulator before executing them, used to undo steps
+    trail: Vec<(usize, i64)>,
+    // incremented by every reset, snapshots of earlier runs can not be restored
+    run: usize,
+    // trail lengths the steps were undone to during this run
+    forks: Vec<usize>,
+}
+
+/// Checkpoint of a `CPU`, only valid in the same run and while the steps leading up to it
+/// have not been undone.
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    ip: i32,
+    acc: i64,
+    step: usize,
+    corrupted_insn: usize,
+    run: usize,
+    forks: usize,
 }
 
 #[derive(Debug)]
@@ -45,6 +212,9 @@ enum CPUError {
     AccumulatorOverflow,
     InstructionPointerOverflow,
     InfiniteLoop(i64),
+    InvalidSnapshot,
+    NotExecuted(usize),
+    StepBudgetExhausted(i64),
 }
 
 type CPUResult<T> = std::result::Result<T, CPUError>;
@@ -59,6 +229,10 @@ impl CPU {
             acc: 0,
             counters,
             corrupted_insn: usize::MAX,
+            step_budget: None,
+            trail: vec![],
+            run: 0,
+            forks: vec![],
         }
     }
 
@@ -67,12 +241,79 @@ impl CPU {
         self.acc = 0;
         self.counters.iter_mut().for_each(|c| *c = 0);
         self.corrupted_insn = usize::MAX;
+        self.trail.clear();
+        self.run += 1;
+        self.forks.clear();
     }
 
     pub fn mark_corrupted(&mut self, ip: usize) {
         self.corrupted_insn = ip;
     }
 
+    /// Enables profiling mode, where revisiting an instruction is not an error.
+    pub fn set_step_budget(&mut self, budget: Option<usize>) {
+        self.step_budget = budget;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ip: self.ip,
+            acc: self.acc,
+            step: self.trail.len(),
+            corrupted_insn: self.corrupted_insn,
+            run: self.run,
+            forks: self.forks.len(),
+        }
+    }
+
+    /// Undoes all steps executed after the snapshot was taken, in time proportional to their number.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> CPUResult<()> {
+        let diverged = self.forks[snapshot.forks.min(self.forks.len())..]
+            .iter()
+            .any(|step| *step < snapshot.step);
+        if snapshot.run != self.run || snapshot.step > self.trail.len() || diverged {
+            return Err(CPUError::InvalidSnapshot);
+        }
+        self.undo(snapshot.step);
+        self.ip = snapshot.ip;
+        self.acc = snapshot.acc;
+        self.corrupted_insn = snapshot.corrupted_insn;
+        Ok(())
+    }
+
+    fn undo(&mut self, step: usize) {
+        if self.trail.len() > step {
+            self.forks.push(step);
+        }
+        while self.trail.len() > step {
+            let (ip, _acc) = self.trail.pop().unwrap();
+            self.counters[ip] -= 1;
+        }
+    }
+
+    /// Goes back to the first execution of the instruction at `ip` and continues from there
+    /// with that instruction flipped.
+    ///
+    /// Steps after that point are discarded, so forking at earlier instructions is still possible
+    /// afterwards but forking at later ones is not.
+    pub fn fork_at(&mut self, ip: usize) -> CPUResult<i64> {
+        let step = self
+            .trail
+            .iter()
+            .position(|(executed, _acc)| *executed == ip)
+            .ok_or(CPUError::NotExecuted(ip))?;
+        let (_ip, acc) = self.trail[step];
+        self.restore(&Snapshot {
+            ip: ip as i32,
+            acc,
+            step,
+            ..self.snapshot()
+        })?;
+        self.mark_corrupted(ip);
+
+        self.run()
+    }
+
     pub fn run(&mut self) -> CPUResult<i64> {
         loop {
             if self.ip < 0 || self.ip as usize > self.code.len() {
@@ -85,11 +326,16 @@ impl CPU {
                 return Ok(self.acc);
             }
 
-            if self.counters[ip] > 0 {
-                return Err(CPUError::InfiniteLoop(self.acc));
+            match self.step_budget {
+                None if self.counters[ip] > 0 => return Err(CPUError::InfiniteLoop(self.acc)),
+                Some(budget) if self.trail.len() >= budget => {
+                    return Err(CPUError::StepBudgetExhausted(self.acc))
+                }
+                _ => {}
             }
 
             self.counters[ip] += 1;
+            self.trail.push((ip, self.acc));
 
             let insn = &self.code[ip];
 
@@ -117,99 +363,776 @@ impl CPU {
     }
 }
 
-fn main() -> Result<()> {
-    let instructions: Vec<Instruction> = read_file("data/8.txt")?;
+impl Instruction {
+    /// The instruction pointer following this instruction at `ip`, may be out of bounds.
+    fn successor(&self, ip: usize) -> i64 {
+        match self {
+            Instruction::Nop(_) | Instruction::Acc(_) => ip as i64 + 1,
+            Instruction::Jmp(a) => ip as i64 + *a as i64,
+        }
+    }
+}
 
-    let mut cpu = CPU::new(instructions.clone());
+/// Marks every instruction from which execution reaches the end of the program,
+/// the entry at index `code.len()` stands for the end itself.
+fn terminating_set(code: &[Instruction]) -> Vec<bool> {
+    let len = code.len();
+    let mut predecessors: Vec<Vec<usize>> = vec![vec![]; len + 1];
+    code.iter().enumerate().for_each(|(ip, insn)| {
+        let target = insn.successor(ip);
+        if target >= 0 && target as usize <= len {
+            predecessors[target as usize].push(ip);
+        }
+    });
 
-    match cpu.run() {
-        Ok(acc) | Err(CPUError::InfiniteLoop(acc)) => println!("{}", acc),
-        Err(e) => return Err(Error::General(format!("{:?}", e))),
+    let mut terminating = vec![false; len + 1];
+    terminating[len] = true;
+    let mut queue = vec![len];
+    while let Some(ip) = queue.pop() {
+        for pred in predecessors[ip].iter() {
+            if !terminating[*pred] {
+                terminating[*pred] = true;
+                queue.push(*pred);
+            }
+        }
     }
 
-    let mut terminating_mask = vec![0_u8; (instructions.len() + 1 + 7) / 8];
-    terminating_mask[instructions.len() / 8] |= 1 << (instructions.len() % 8);
-    loop {
-        let mut updated = false;
-        let mut count = 0;
-        instructions.iter().enumerate().for_each(|(ip, insn)| {
-            if terminating_mask[ip / 8] & (1 << (ip % 8)) == 0 {
-                let target = match insn {
-                    Instruction::Nop(_) | Instruction::Acc(_) => ip + 1,
-                    Instruction::Jmp(a) => (ip as i64 + *a as i64) as usize,
-                };
-
-                if terminating_mask[target / 8] & (1 << (target % 8)) != 0 {
-                    updated = true;
-                    count += 1;
-                    terminating_mask[ip / 8] |= 1 << (ip % 8);
-                }
+    terminating
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Edit {
+    /// swap `jmp` and `nop`
+    Flip(usize),
+    /// change the offset of a `jmp`
+    Retarget(usize, i32),
+    /// skip the instruction, jump targets of other instructions are unchanged
+    Delete(usize),
+}
+
+impl Edit {
+    fn ip(&self) -> usize {
+        match self {
+            Edit::Flip(ip) | Edit::Retarget(ip, _) | Edit::Delete(ip) => *ip,
+        }
+    }
+
+    fn apply(&self, code: &mut [Instruction]) {
+        let ip = self.ip();
+        code[ip] = match (self, &code[ip]) {
+            (Edit::Flip(_), Instruction::Jmp(a)) => Instruction::Nop(*a),
+            (Edit::Flip(_), Instruction::Nop(a)) => Instruction::Jmp(*a),
+            (Edit::Retarget(_, a), Instruction::Jmp(_)) => Instruction::Jmp(*a),
+            (Edit::Delete(_), _) => Instruction::Nop(0),
+            (_, insn) => insn.clone(),
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RepairOptions {
+    max_edits: usize,
+    flips: bool,
+    retargets: bool,
+    deletes: bool,
+    target_acc: Option<i64>,
+}
+
+impl Default for RepairOptions {
+    fn default() -> Self {
+        Self {
+            max_edits: 1,
+            flips: true,
+            retargets: false,
+            deletes: false,
+            target_acc: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Repair {
+    edits: Vec<Edit>,
+    acc: i64,
+}
+
+fn candidate_edits(
+    ip: usize,
+    insn: &Instruction,
+    len: usize,
+    options: &RepairOptions,
+) -> Vec<Edit> {
+    let mut edits = vec![];
+    match insn {
+        Instruction::Acc(_) => {
+            if options.deletes {
+                edits.push(Edit::Delete(ip));
             }
-        });
+        }
+        Instruction::Nop(_) => {
+            if options.flips {
+                edits.push(Edit::Flip(ip));
+            }
+        }
+        Instruction::Jmp(a) => {
+            if options.flips {
+                edits.push(Edit::Flip(ip));
+            }
+            if options.retargets {
+                (0..=len as i64)
+                    .map(|target| target - ip as i64)
+                    .filter(|offset| *offset != *a as i64)
+                    .for_each(|offset| edits.push(Edit::Retarget(ip, offset as i32)));
+            }
+            // a deleted `jmp` runs like a flipped one
+            if options.deletes && !options.flips {
+                edits.push(Edit::Delete(ip));
+            }
+        }
+    }
+    edits
+}
+
+fn search_repairs(
+    code: &mut Vec<Instruction>,
+    edits: &mut Vec<Edit>,
+    budget: usize,
+    options: &RepairOptions,
+    seen: &mut HashSet<Vec<Edit>>,
+    found: &mut BTreeMap<Vec<Edit>, i64>,
+) {
+    let mut key = edits.clone();
+    key.sort();
+    if !seen.insert(key.clone()) {
+        return;
+    }
+
+    let mut cpu = CPU::new(code.clone());
+    if let Ok(acc) = cpu.run() {
+        if options.target_acc.map_or(true, |target| target == acc) {
+            found.insert(key, acc);
+            return;
+        }
+    }
+
+    if budget == 0 {
+        return;
+    }
+
+    // with a single edit left, the edited instruction has to continue on a path that
+    // already reaches the end, since that path can not pass the edited instruction again
+    let terminating = if budget == 1 {
+        Some(terminating_set(code))
+    } else {
+        None
+    };
+
+    let len = code.len();
+    let candidates: Vec<Edit> = (0..len)
+        .filter(|ip| cpu.counters[*ip] > 0) // edits elsewhere would not change the execution
+        .filter(|ip| !edits.iter().any(|edit| edit.ip() == *ip))
+        .flat_map(|ip| candidate_edits(ip, &code[ip], len, options))
+        .collect();
+
+    for edit in candidates {
+        let ip = edit.ip();
+        let original = code[ip].clone();
+        edit.apply(code);
+
+        let target = code[ip].successor(ip);
+        let reaches_end = match &terminating {
+            None => true,
+            Some(terminating) => {
+                target >= 0 && target as usize <= len && terminating[target as usize]
+            }
+        };
 
-        if !updated {
-            break;
+        if reaches_end {
+            edits.push(edit);
+            search_repairs(code, edits, budget - 1, options, seen, found);
+            edits.pop();
         }
+
+        code[ip] = original;
+    }
+}
+
+/// Finds all minimal sets of edits that make the program terminate, ordered by number of edits.
+fn repair(code: &[Instruction], options: &RepairOptions) -> Vec<Repair> {
+    let mut found = BTreeMap::new();
+    search_repairs(
+        &mut code.to_vec(),
+        &mut vec![],
+        options.max_edits,
+        options,
+        &mut HashSet::new(),
+        &mut found,
+    );
+
+    let mut repairs: Vec<Repair> = found
+        .iter()
+        .filter(|(edits, _)| {
+            // drop solutions that contain a smaller solution
+            !found.keys().any(|other| {
+                other.len() < edits.len() && other.iter().all(|edit| edits.contains(edit))
+            })
+        })
+        .map(|(edits, acc)| Repair {
+            edits: edits.clone(),
+            acc: *acc,
+        })
+        .collect();
+    repairs.sort_by_key(|repair| repair.edits.len());
+
+    repairs
+}
+
+#[derive(Debug)]
+enum FlipRepairError {
+    NotLooping(i64),
+    NoFix,
+    Ambiguous(Vec<usize>),
+    CPU(CPUError),
+}
+
+impl From<CPUError> for FlipRepairError {
+    fn from(e: CPUError) -> Self {
+        FlipRepairError::CPU(e)
+    }
+}
+
+/// Finds the single `jmp`/`nop` flip that makes the program terminate, returning its ip and
+/// the final accumulator. The program is run again from the start, so the CPU may have been
+/// used before.
+///
+/// Instructions visited by the looping run can not reach the end, so flipping one of them
+/// fixes the program exactly if the flipped successor is in the terminating set.
+fn repair_single_flip(cpu: &mut CPU) -> std::result::Result<(usize, i64), FlipRepairError> {
+    cpu.reset();
+    match cpu.run() {
+        Ok(acc) => return Err(FlipRepairError::NotLooping(acc)),
+        Err(CPUError::InfiniteLoop(_)) => {}
+        Err(e) => return Err(e.into()),
     }
 
-    let counters = cpu.counters.clone();
+    let code = &cpu.code;
+    let terminating = terminating_set(code);
+    let len = code.len() as i64;
 
-    instructions
+    let fixes: Vec<usize> = code
         .iter()
         .enumerate()
-        .filter(|(ip, insn)| match insn {
-            Instruction::Acc(_) => false,
-            Instruction::Jmp(_) => {
-                let target_if_nop = *ip + 1;
-                terminating_mask[target_if_nop / 8] & (1 << (target_if_nop % 8)) != 0
+        .filter(|(ip, _insn)| cpu.counters[*ip] > 0)
+        .filter(|(ip, insn)| {
+            let target = match insn {
+                Instruction::Acc(_) => return false,
+                Instruction::Jmp(_) => *ip as i64 + 1,
+                Instruction::Nop(a) => *ip as i64 + *a as i64,
+            };
+            target >= 0 && target <= len && terminating[target as usize]
+        })
+        .map(|(ip, _insn)| ip)
+        .collect();
+
+    match fixes.as_slice() {
+        [] => Err(FlipRepairError::NoFix),
+        [ip] => Ok((*ip, cpu.fork_at(*ip)?)),
+        _ => Err(FlipRepairError::Ambiguous(fixes)),
+    }
+}
+
+mod profile {
+    use super::Instruction;
+    use super::CPU;
+    use super::{Edit, Error, Result};
+    use std::fmt::{Display, Formatter};
+    use std::ops::Range;
+
+    const BAR_WIDTH: usize = 40;
+
+    #[derive(Debug, Clone, Copy)]
+    pub(super) enum Format {
+        Text,
+        Folded,
+    }
+
+    impl std::str::FromStr for Format {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s {
+                "text" => Ok(Format::Text),
+                "folded" => Ok(Format::Folded),
+                _ => Err(Error::General(format!("Invalid profile format {}", s))),
             }
-            Instruction::Nop(a) => {
-                let target_if_jmp = (*ip as i64 + *a as i64) as usize;
-                terminating_mask[target_if_jmp / 8] & (1 << (target_if_jmp % 8)) != 0
+        }
+    }
+
+    /// A backwards jump from `latch` to `header`, approximated as covering all instructions in between.
+    /// Only loops that were actually taken count for nesting.
+    #[derive(Debug, Clone)]
+    struct Loop {
+        header: usize,
+        latch: usize,
+    }
+
+    #[derive(Debug, Clone)]
+    pub(super) struct Profile {
+        code: Vec<Instruction>,
+        counters: Vec<i32>,
+        blocks: Vec<Range<usize>>,
+        loops: Vec<Loop>,
+    }
+
+    impl Profile {
+        pub(super) fn new(cpu: &CPU) -> Self {
+            let mut code = cpu.code.clone();
+            if cpu.corrupted_insn < code.len() {
+                Edit::Flip(cpu.corrupted_insn).apply(&mut code);
             }
-        })
-        .filter(|(ip, _insn)| counters[*ip as usize] > 0) // only try actually executed instructions
-        .try_for_each(|(ip, _insn)| -> Result<()> {
-            cpu.reset();
-            cpu.mark_corrupted(ip);
-
-            match cpu.run() {
-                Ok(acc) => {
-                    println!("{}", acc);
-                }
-                Err(CPUError::InfiniteLoop(_)) => {
-                    // still an infinite loop, ignore
+            let len = code.len();
+
+            let mut leaders = vec![false; len + 1];
+            leaders[0] = true;
+            leaders[len] = true;
+            code.iter().enumerate().for_each(|(ip, insn)| {
+                if let Instruction::Jmp(_) = insn {
+                    leaders[ip + 1] = true;
+                    let target = insn.successor(ip);
+                    if target >= 0 && target as usize <= len {
+                        leaders[target as usize] = true;
+                    }
                 }
-                Err(e) => return Err(Error::General(format!("{:?}", e))),
+            });
+            let starts: Vec<usize> = (0..=len).filter(|ip| leaders[*ip]).collect();
+            let blocks = starts
+                .windows(2)
+                .map(|window| window[0]..window[1])
+                .collect();
+
+            let loops = code
+                .iter()
+                .enumerate()
+                .filter_map(|(ip, insn)| match insn {
+                    Instruction::Jmp(a) if *a <= 0 => Some(Loop {
+                        header: insn.successor(ip).max(0) as usize,
+                        latch: ip,
+                    }),
+                    _ => None,
+                })
+                .collect();
+
+            Self {
+                code,
+                counters: cpu.counters.clone(),
+                blocks,
+                loops,
             }
+        }
 
-            Ok(())
-        })?;
+        fn block_hits(&self, block: &Range<usize>) -> i32 {
+            self.counters[block.start]
+        }
 
-    // turns out the above was overkill, could have just tried marking every nop or jmp as corrupt
+        fn enclosing_loops(&self, ip: usize) -> Vec<&Loop> {
+            let mut loops: Vec<&Loop> = self
+                .loops
+                .iter()
+                .filter(|l| self.counters[l.latch] > 0 && l.header <= ip && ip <= l.latch)
+                .collect();
+            // outermost first
+            loops.sort_by_key(|l| (l.header, std::cmp::Reverse(l.latch)));
+            loops
+        }
 
-    instructions
-        .iter()
-        .enumerate()
-        .filter(|(_ip, insn)| match insn {
-            Instruction::Acc(_) => false,
-            Instruction::Jmp(_) | Instruction::Nop(_) => true,
-        })
-        .try_for_each(|(ip, _insn)| -> Result<()> {
-            cpu.reset();
-            cpu.mark_corrupted(ip);
+        /// Folded stacks as consumed by `flamegraph.pl`, one line per executed basic block
+        /// with the enclosing loops as frames.
+        pub(super) fn folded_stacks(&self) -> String {
+            let mut output = String::new();
+            self.blocks
+                .iter()
+                .filter(|block| self.block_hits(block) > 0)
+                .for_each(|block| {
+                    let mut stack = vec!["main".to_string()];
+                    self.enclosing_loops(block.start)
+                        .iter()
+                        .for_each(|l| stack.push(format!("loop_{}_{}", l.header, l.latch)));
+                    stack.push(format!("block_{}_{}", block.start, block.end - 1));
+                    let steps: i64 = self.counters[block.clone()].iter().map(|c| *c as i64).sum();
+                    output.push_str(&format!("{} {}\n", stack.join(";"), steps));
+                });
+            output
+        }
+
+        pub(super) fn report(&self, format: Format) -> String {
+            match format {
+                Format::Text => self.to_string(),
+                Format::Folded => self.folded_stacks(),
+            }
+        }
+    }
+
+    fn bar(count: i32, max: i32) -> String {
+        let width = if max > 0 {
+            (count as i64 * BAR_WIDTH as i64 / max as i64) as usize
+        } else {
+            0
+        };
+        "#".repeat(width)
+    }
+
+    impl Display for Profile {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let max_insn = self.counters.iter().copied().max().unwrap_or(0);
+            let max_block = self
+                .blocks
+                .iter()
+                .map(|block| self.block_hits(block))
+                .max()
+                .unwrap_or(0);
+
+            f.write_str("Blocks:\n")?;
+            for block in self.blocks.iter() {
+                let hits = self.block_hits(block);
+                if hits > 0 {
+                    f.write_fmt(format_args!(
+                        "{:>5}..{:<5} {:>10} {}\n",
+                        block.start,
+                        block.end,
+                        hits,
+                        bar(hits, max_block)
+                    ))?;
+                }
+            }
 
-            match cpu.run() {
-                Ok(acc) => {
-                    println!("{}", acc);
+            f.write_str("\nListing:\n")?;
+            for block in self.blocks.iter() {
+                if self.block_hits(block) == 0 {
+                    f.write_fmt(format_args!(
+                        "      ... {} instructions not executed\n",
+                        block.len()
+                    ))?;
+                    continue;
                 }
-                Err(CPUError::InfiniteLoop(_)) => {
-                    // still an infinite loop, ignore
+                for ip in block.clone() {
+                    let depth = self.enclosing_loops(ip).len();
+                    f.write_fmt(format_args!(
+                        "{:>5}: {:<10} {:>10} {}{}\n",
+                        ip,
+                        self.code[ip].to_string(),
+                        self.counters[ip],
+                        "|".repeat(depth),
+                        bar(self.counters[ip], max_insn)
+                    ))?;
                 }
-                Err(e) => return Err(Error::General(format!("{:?}", e))),
+                f.write_str("\n")?;
+            }
+
+            f.write_str("Loops:\n")?;
+            let mut max_depth = 0;
+            for l in self.loops.iter().filter(|l| self.counters[l.latch] > 0) {
+                let depth = self.enclosing_loops(l.header).len();
+                max_depth = max_depth.max(depth);
+                f.write_fmt(format_args!(
+                    "{:>5}..={:<5} depth {:>2} size {:>4} iterations {:>10}\n",
+                    l.header,
+                    l.latch,
+                    depth,
+                    l.latch - l.header + 1,
+                    self.counters[l.latch]
+                ))?;
             }
+            f.write_fmt(format_args!(
+                "{} loops, {} executed, max nesting depth {}\n",
+                self.loops.len(),
+                self.loops
+                    .iter()
+                    .filter(|l| self.counters[l.latch] > 0)
+                    .count(),
+                max_depth
+            ))?;
 
             Ok(())
-        })?;
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let instructions: Vec<Instruction> = read_file("data/8.txt")?;
+
+    // `encode <file>` stores the program as bytecode, `decode <file>` lists a bytecode file,
+    // `profile [text|folded]` reports where a run of 10000 steps spends its time,
+    // `repair <max edits>` lists all minimal repairs using flips and deletions
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    match command.as_deref() {
+        Some("encode") => {
+            let path = args
+                .next()
+                .ok_or_else(|| Error::General("Missing output file".into()))?;
+            std::fs::write(path, bytecode::encode(&instructions))?;
+            return Ok(());
+        }
+        Some("decode") => {
+            let path = args
+                .next()
+                .ok_or_else(|| Error::General("Missing input file".into()))?;
+            for insn in bytecode::decode(&std::fs::read(path)?)? {
+                println!("{}", insn);
+            }
+            return Ok(());
+        }
+        Some("repair") => {
+            let options = RepairOptions {
+                max_edits: args.next().map_or(Ok(1), |arg| arg.parse())?,
+                deletes: true,
+                ..RepairOptions::default()
+            };
+            for repair in repair(&instructions, &options) {
+                println!("{:?}", repair);
+            }
+            return Ok(());
+        }
+        Some("profile") => {
+            let format = args
+                .next()
+                .map_or(Ok(profile::Format::Text), |arg| arg.parse())?;
+            let mut profiler = CPU::new(instructions);
+            profiler.set_step_budget(Some(10_000));
+            match profiler.run() {
+                Ok(_) | Err(CPUError::StepBudgetExhausted(_)) => {}
+                Err(e) => return Err(Error::General(format!("{:?}", e))),
+            }
+            println!("{}", profile::Profile::new(&profiler).report(format));
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let mut cpu = CPU::new(instructions);
+
+    match cpu.run() {
+        Ok(acc) | Err(CPUError::InfiniteLoop(acc)) => println!("{}", acc),
+        Err(e) => return Err(Error::General(format!("{:?}", e))),
+    }
+
+    match repair_single_flip(&mut cpu) {
+        Ok((_ip, acc)) => println!("{}", acc),
+        Err(e) => return Err(Error::General(format!("{:?}", e))),
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn puzzle() -> Vec<Instruction> {
+        read_file("data/8.txt").unwrap()
+    }
+
+    #[test]
+    fn minimal_repairs() {
+        let instructions = puzzle();
+        let repairs = repair(&instructions, &RepairOptions::default());
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].edits, vec![Edit::Flip(196)]);
+        assert_eq!(
+            repair_single_flip(&mut CPU::new(instructions.clone())).unwrap(),
+            (196, repairs[0].acc)
+        );
+
+        let options = RepairOptions {
+            max_edits: 2,
+            deletes: true,
+            ..RepairOptions::default()
+        };
+        let repairs = repair(&instructions, &options);
+        assert_eq!(repairs.len(), 3);
+        let mut codes = vec![];
+        for repair in repairs {
+            let mut code = instructions.clone();
+            repair.edits.iter().for_each(|edit| edit.apply(&mut code));
+            assert_eq!(CPU::new(code.clone()).run().ok(), Some(repair.acc));
+            assert!(
+                !codes.contains(&code),
+                "{:?} repeats another repair",
+                repair
+            );
+            codes.push(code);
+        }
+    }
+
+    #[test]
+    fn repairs_reaching_target_acc() {
+        let options = RepairOptions {
+            max_edits: 2,
+            target_acc: Some(571),
+            ..RepairOptions::default()
+        };
+        // the single flip terminates with another value, so two edits are needed
+        assert_eq!(
+            repair(&puzzle(), &options),
+            vec![Repair {
+                edits: vec![Edit::Flip(51), Edit::Flip(52)],
+                acc: 571,
+            }]
+        );
+
+        let options = RepairOptions {
+            target_acc: Some(0),
+            ..options
+        };
+        assert!(repair(&puzzle(), &options).is_empty());
+    }
+
+    #[test]
+    fn single_flip_matches_brute_force() {
+        let instructions = puzzle();
+        let mut cpu = CPU::new(instructions.clone());
+        let fixed: Vec<(usize, i64)> = instructions
+            .iter()
+            .enumerate()
+            .filter(|(_ip, insn)| !matches!(insn, Instruction::Acc(_)))
+            .filter_map(|(ip, _insn)| {
+                cpu.reset();
+                cpu.mark_corrupted(ip);
+                cpu.run().ok().map(|acc| (ip, acc))
+            })
+            .collect();
+        // the flip is searched for on the same CPU, after its runs with other corruptions
+        assert_eq!(fixed, vec![repair_single_flip(&mut cpu).unwrap()]);
+    }
+
+    #[test]
+    fn forks_match_single_flip() {
+        // latest instructions first, so the earlier steps stay intact
+        let instructions = puzzle();
+        let mut cpu = CPU::new(instructions.clone());
+        let start = cpu.snapshot();
+        assert!(matches!(cpu.run(), Err(CPUError::InfiniteLoop(_))));
+        let executed: Vec<usize> = cpu.trail.iter().map(|(ip, _acc)| *ip).collect();
+        let fixed: Vec<(usize, i64)> = executed
+            .iter()
+            .rev()
+            .filter(|ip| !matches!(instructions[**ip], Instruction::Acc(_)))
+            .filter_map(|ip| match cpu.fork_at(*ip) {
+                Ok(acc) => Some((*ip, acc)),
+                Err(CPUError::InfiniteLoop(_)) => None,
+                Err(e) => panic!("{:?}", e),
+            })
+            .collect();
+        cpu.restore(&start).unwrap();
+        assert_eq!(fixed, vec![repair_single_flip(&mut cpu).unwrap()]);
+    }
+
+    #[test]
+    fn snapshots_of_other_runs_are_rejected() {
+        let mut cpu = CPU::new(puzzle());
+        let start = cpu.snapshot();
+        assert!(cpu.run().is_err());
+        let looped = cpu.snapshot();
+        cpu.restore(&start).unwrap();
+        cpu.restore(&start).unwrap();
+        // the steps leading up to it were undone, even though the trail is as long again
+        assert!(cpu.run().is_err());
+        assert!(matches!(
+            cpu.restore(&looped),
+            Err(CPUError::InvalidSnapshot)
+        ));
+
+        cpu.reset();
+        assert!(cpu.run().is_err());
+        assert!(matches!(
+            cpu.restore(&start),
+            Err(CPUError::InvalidSnapshot)
+        ));
+    }
+
+    #[test]
+    fn profile_of_nested_loops() {
+        // the inner loop 2..=5 is entered from the middle of the outer loop 1..=6
+        let code: Vec<Instruction> = [
+            "nop +0", "jmp +3", "acc +2", "jmp +3", "acc +1", "jmp -3", "jmp -5",
+        ]
+        .iter()
+        .map(|line| line.parse().unwrap())
+        .collect();
+        let mut cpu = CPU::new(code);
+        cpu.set_step_budget(Some(1 + 3 * 6));
+        assert!(matches!(cpu.run(), Err(CPUError::StepBudgetExhausted(9))));
+        let profile = profile::Profile::new(&cpu);
+
+        assert_eq!(
+            profile.report(profile::Format::Folded),
+            "main;block_0_0 1\n\
+             main;loop_1_6;block_1_1 3\n\
+             main;loop_1_6;loop_2_5;block_2_3 6\n\
+             main;loop_1_6;loop_2_5;block_4_5 6\n\
+             main;loop_1_6;block_6_6 3\n"
+        );
+
+        let text = profile.report(profile::Format::Text);
+        let words = |line: &str| {
+            line.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let blocks: Vec<Vec<String>> = text
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| words(line)[..2].to_vec())
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                ["0..1", "1"],
+                ["1..2", "3"],
+                ["2..4", "3"],
+                ["4..6", "3"],
+                ["6..7", "3"]
+            ]
+            .iter()
+            .map(|block| block
+                .iter()
+                .map(|word| word.to_string())
+                .collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+        );
+        let loops: Vec<Vec<String>> = text
+            .lines()
+            .skip_while(|line| *line != "Loops:")
+            .skip(1)
+            .map(words)
+            .collect();
+        assert_eq!(loops[0].join(" "), "2..=5 depth 2 size 4 iterations 3");
+        assert_eq!(loops[1].join(" "), "1..=6 depth 1 size 6 iterations 3");
+        assert_eq!(
+            loops[2].join(" "),
+            "2 loops, 2 executed, max nesting depth 2"
+        );
+    }
+
+    #[test]
+    fn bytecode_round_trip() {
+        let instructions = puzzle();
+        let encoded = bytecode::encode(&instructions);
+        let decoded = bytecode::decode(&encoded).unwrap();
+        assert_eq!(decoded, instructions);
+
+        let text: Vec<String> = decoded.iter().map(|insn| insn.to_string()).collect();
+        let source = std::fs::read_to_string("data/8.txt").unwrap();
+        assert_eq!(text, source.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bytecode_rejects_corruption() {
+        let mut encoded = bytecode::encode(&puzzle());
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(bytecode::decode(&encoded).is_err());
+        assert!(bytecode::decode(&encoded[..3]).is_err());
+    }
+}
@@ -72,6 +72,33 @@ where
         .collect()
 }
 
+/// Small xorshift pseudo random generator for generating test inputs.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    /// A zero state would only ever produce zeros, so a seed of 0 is replaced by a fixed one.
+    pub fn new(seed: u64) -> Self {
+        if seed == 0 {
+            Self(0x9e37_79b9_7f4a_7c15)
+        } else {
+            Self(seed)
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniformly distributed number in `0..n`, ignoring the slight modulo bias.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
 #[macro_export]
 macro_rules! regex {
     ($re:literal $(,)?) => {{
@@ -85,6 +112,7 @@ pub mod prelude {
     pub use super::regex;
     pub use super::Error;
     pub use super::Result;
+    pub use super::XorShift;
 
     pub use regex::Regex;
 }